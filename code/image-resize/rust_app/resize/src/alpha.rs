@@ -0,0 +1,27 @@
+use fast_image_resize::{images::Image, MulDiv, PixelType};
+
+// Blurs run on straight alpha buffers, so their color channels are scaled by alpha for the duration
+// of the blur, otherwise the color of transparent pixels bleeds into the edges as dark fringes
+pub fn premultiply(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut image = Image::from_slice_u8(width, height, buf, pixel_type)?;
+    MulDiv::new().multiply_alpha_inplace(&mut image)?;
+
+    Ok(())
+}
+
+pub fn unpremultiply(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    pixel_type: PixelType,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut image = Image::from_slice_u8(width, height, buf, pixel_type)?;
+    MulDiv::new().divide_alpha_inplace(&mut image)?;
+
+    Ok(())
+}
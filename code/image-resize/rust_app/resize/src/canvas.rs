@@ -0,0 +1,104 @@
+use std::fmt::{Display, Formatter};
+
+use crate::color::Color;
+
+const MAX_BORDER_WIDTH: u32 = 100;
+const MAX_PADDING: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Border {
+    pub width: u32,
+    pub color: Color,
+}
+
+impl Border {
+    // Accepts `width` or `width,color`, e.g. `2` or `2,cccccc`
+    pub fn from_param(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(2, ',');
+
+        let width = parts
+            .next()?
+            .parse::<u32>()
+            .ok()
+            .filter(|w| *w > 0)?
+            .min(MAX_BORDER_WIDTH);
+
+        let color = match parts.next() {
+            Some(color) => Color::from_hex(color)?,
+            None => Color::BLACK,
+        };
+
+        Some(Border { width, color })
+    }
+}
+
+impl Display for Border {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{},{}", self.width, self.color)
+    }
+}
+
+pub fn parse_padding(value: &str) -> Option<u32> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|p| *p > 0)
+        .map(|p| p.min(MAX_PADDING))
+}
+
+// Space added on each side of the image by the border and the padding
+pub fn offset(border: Option<Border>, padding: u32) -> u32 {
+    border.map(|b| b.width).unwrap_or(0) + padding
+}
+
+// Places the image in the middle of a larger canvas, framed by the border and surrounded by the
+// padding. Returns the new buffer with its dimensions.
+pub fn extend(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    channels: usize,
+    border: Option<Border>,
+    padding: u32,
+    background: Color,
+) -> (Vec<u8>, u32, u32) {
+    let offset = offset(border, padding);
+
+    let new_width = width + offset * 2;
+    let new_height = height + offset * 2;
+
+    let pixel =
+        |color: Color| -> Vec<u8> { [color.r, color.g, color.b, color.a][..channels].to_vec() };
+
+    let background = pixel(background);
+    let mut canvas = background.repeat((new_width * new_height) as usize);
+
+    if let Some(border) = border {
+        let frame = pixel(border.color);
+        let (start, end) = (padding, new_width - padding);
+
+        for y in padding..new_height - padding {
+            let row = (y * new_width) as usize * channels;
+            let inside = y >= offset && y < new_height - offset;
+
+            for x in start..end {
+                if inside && x >= offset && x < new_width - offset {
+                    continue;
+                }
+
+                let i = row + x as usize * channels;
+                canvas[i..i + channels].copy_from_slice(&frame);
+            }
+        }
+    }
+
+    let stride = width as usize * channels;
+    let new_stride = new_width as usize * channels;
+
+    for (y, line) in buf.chunks_exact(stride).enumerate() {
+        let i = (y + offset as usize) * new_stride + offset as usize * channels;
+        canvas[i..i + stride].copy_from_slice(line);
+    }
+
+    (canvas, new_width, new_height)
+}
@@ -0,0 +1,95 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+
+    pub const BLACK: Color = Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+
+    // Accepts `rgb`, `rrggbb` and `rrggbbaa`, with or without a leading `#`
+    pub fn from_hex(value: &str) -> Option<Self> {
+        let hex = value.trim_start_matches('#');
+
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+
+        match hex.len() {
+            3 => {
+                let short = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|v| v * 17);
+
+                Some(Color {
+                    r: short(0)?,
+                    g: short(1)?,
+                    b: short(2)?,
+                    a: 255,
+                })
+            }
+            6 => Some(Color {
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+                a: 255,
+            }),
+            8 => Some(Color {
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+                a: channel(6)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Composites an RGBA8 buffer onto a solid background and returns an RGB8 buffer
+pub fn flatten(rgba: &[u8], bg: &Color) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = pixel[3] as u32;
+        let blend =
+            |c: u8, b: u8| ((c as u32 * alpha + b as u32 * (255 - alpha) + 127) / 255) as u8;
+
+        rgb.push(blend(pixel[0], bg.r));
+        rgb.push(blend(pixel[1], bg.g));
+        rgb.push(blend(pixel[2], bg.b));
+    }
+
+    rgb
+}
+
+pub fn has_transparency(rgba: &[u8]) -> bool {
+    rgba.chunks_exact(4).any(|pixel| pixel[3] < 255)
+}
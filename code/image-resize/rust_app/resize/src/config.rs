@@ -2,7 +2,52 @@ use std::env;
 
 use lazy_static::lazy_static;
 
-use crate::{ImageQuality, TransformMode};
+use crate::{Color, ImageQuality, TransformMode};
+
+#[derive(Debug, Clone)]
+pub struct QualityTable {
+    pub low: u8,
+    pub medium: u8,
+    pub high: u8,
+    pub best: u8,
+}
+
+impl QualityTable {
+    // Reads a `low,medium,high,best` list such as `25,50,75,100`
+    fn from_env(name: &str, default: QualityTable) -> Self {
+        Self::parse_env(name).unwrap_or(default)
+    }
+
+    fn parse_env(name: &str) -> Option<Self> {
+        let values: Option<Vec<u8>> = env::var(name).ok().and_then(|val| {
+            val.split(',')
+                .map(|v| v.trim().parse::<u8>().ok().map(|v| v.clamp(1, 100)))
+                .collect()
+        });
+
+        match values.as_deref() {
+            Some(&[low, medium, high, best]) => Some(QualityTable {
+                low,
+                medium,
+                high,
+                best,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, quality: &ImageQuality) -> u8 {
+        match quality {
+            ImageQuality::Low => self.low,
+            ImageQuality::Medium => self.medium,
+            ImageQuality::High => self.high,
+            ImageQuality::Best => self.best,
+            ImageQuality::Value(value) => *value,
+            // Used when the format cannot be measured for auto quality
+            ImageQuality::Auto => self.high,
+        }
+    }
+}
 
 pub struct Config {
     pub rounding_value: u32,
@@ -14,6 +59,20 @@ pub struct Config {
     pub default_height: u32,
     pub default_quality: ImageQuality,
     pub default_transform: TransformMode,
+    pub default_background: Color,
+    pub jpeg_quality: QualityTable,
+    pub jpeg_progressive: bool,
+    pub png_quality: QualityTable,
+    pub avif_quality: QualityTable,
+    pub webp_quality: Option<QualityTable>,
+    pub auto_quality_ssim: f64,
+    pub palette_dither: bool,
+    pub png_optimize_timeout: u64,
+    pub linear_resize: bool,
+    pub max_input_bytes: usize,
+    pub max_decoded_pixels: u64,
+    pub max_frames: u32,
+    pub max_output_pixels: u64,
 }
 
 impl Config {
@@ -27,18 +86,94 @@ impl Config {
         let max_height = env::var("MAX_HEIGHT").ok().and_then(parse_number).unwrap_or(4100);
         let default_width = env::var("DEFAULT_WIDTH").ok().and_then(parse_number).unwrap_or(640);
         let default_height = env::var("DEFAULT_HEIGHT").ok().and_then(parse_number).unwrap_or(400);
-        let default_quality = match env::var("DEFAULT_QUALITY").ok().as_deref() {
-            Some("l") => ImageQuality::Low,
-            Some("m") => ImageQuality::Medium,
-            Some("h") => ImageQuality::High,
-            Some("b") => ImageQuality::Best,
-            _ => ImageQuality::High
-        };
+        let default_quality = env::var("DEFAULT_QUALITY")
+            .ok()
+            .and_then(|val| ImageQuality::from_param(&val))
+            .unwrap_or(ImageQuality::High);
         let default_transform = match env::var("DEFAULT_TRANSFORM").ok().as_deref() {
             Some("c") => TransformMode::Crop,
             Some("f") => TransformMode::Fit,
             _ => TransformMode::Fit,
         };
+        let default_background = env::var("DEFAULT_BACKGROUND")
+            .ok()
+            .and_then(|val| Color::from_hex(&val))
+            .unwrap_or(Color::WHITE);
+        let jpeg_quality = QualityTable::from_env(
+            "JPEG_QUALITY",
+            QualityTable {
+                low: 25,
+                medium: 50,
+                high: 75,
+                best: 100,
+            },
+        );
+        let jpeg_progressive = env::var("JPEG_PROGRESSIVE")
+            .ok()
+            .and_then(|val| val.parse::<bool>().ok())
+            .unwrap_or(true);
+        let png_quality = QualityTable::from_env(
+            "PNG_QUALITY",
+            QualityTable {
+                low: 25,
+                medium: 50,
+                high: 75,
+                best: 100,
+            },
+        );
+        let avif_quality = QualityTable::from_env(
+            "AVIF_QUALITY",
+            QualityTable {
+                low: 30,
+                medium: 45,
+                high: 60,
+                best: 80,
+            },
+        );
+        // Without a table the named levels keep WebP lossless
+        let webp_quality = QualityTable::parse_env("WEBP_QUALITY");
+
+        let auto_quality_ssim = env::var("AUTO_QUALITY_SSIM")
+            .ok()
+            .and_then(|val| val.parse::<f64>().ok())
+            .filter(|val| (0.0..=1.0).contains(val))
+            .unwrap_or(0.97);
+
+        let palette_dither = env::var("PALETTE_DITHER")
+            .ok()
+            .and_then(|val| val.parse::<bool>().ok())
+            .unwrap_or(true);
+
+        let png_optimize_timeout = env::var("PNG_OPTIMIZE_TIMEOUT")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(2000);
+
+        let linear_resize = env::var("LINEAR_RESIZE")
+            .ok()
+            .and_then(|val| val.parse::<bool>().ok())
+            .unwrap_or(false);
+
+        // Inputs over these limits are rejected before they are decoded or resized
+        let max_input_bytes = env::var("MAX_INPUT_BYTES")
+            .ok()
+            .and_then(|val| val.parse::<usize>().ok())
+            .unwrap_or(50_000_000);
+
+        let max_decoded_pixels = env::var("MAX_DECODED_PIXELS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(25_000_000);
+
+        let max_frames = env::var("MAX_FRAMES")
+            .ok()
+            .and_then(|val| val.parse::<u32>().ok())
+            .unwrap_or(100);
+
+        let max_output_pixels = env::var("MAX_OUTPUT_PIXELS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(max_width as u64 * max_height as u64);
 
         Config {
             rounding_value,
@@ -50,6 +185,20 @@ impl Config {
             default_height,
             default_quality,
             default_transform,
+            default_background,
+            jpeg_quality,
+            jpeg_progressive,
+            png_quality,
+            avif_quality,
+            webp_quality,
+            auto_quality_ssim,
+            palette_dither,
+            png_optimize_timeout,
+            linear_resize,
+            max_input_bytes,
+            max_decoded_pixels,
+            max_frames,
+            max_output_pixels,
         }
     }
 }
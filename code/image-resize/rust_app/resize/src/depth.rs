@@ -0,0 +1,85 @@
+use image::ColorType;
+use libblur::FastBlurChannels;
+
+// 4x4 Bayer matrix, the thresholds spread the rounding of the dropped low byte over neighbors
+const BAYER: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+pub fn is_high_depth(color: ColorType) -> bool {
+    matches!(
+        color,
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16
+    )
+}
+
+fn samples(buf: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    buf.chunks_exact(2)
+        .map(|s| u16::from_ne_bytes([s[0], s[1]]))
+}
+
+// Reduces 16-bit samples to 8-bit with ordered dithering, so smooth gradients do not band. Alpha
+// is rounded instead, as noise along edges shows up as fringes.
+pub fn to_8bit(
+    buf: &[u8],
+    width: u32,
+    color: ColorType,
+) -> Result<(Vec<u8>, ColorType), Box<dyn std::error::Error>> {
+    let color8 = match color {
+        ColorType::L16 => ColorType::L8,
+        ColorType::La16 => ColorType::La8,
+        ColorType::Rgb16 => ColorType::Rgb8,
+        ColorType::Rgba16 => ColorType::Rgba8,
+        _ => return Err(format!("Unsupported high bit depth color type {:?}", color).into()),
+    };
+
+    let channels = color.channel_count() as usize;
+    let alpha = color.has_alpha().then_some(channels - 1);
+
+    let pixels = samples(buf)
+        .enumerate()
+        .map(|(i, sample)| {
+            let (pixel, channel) = (i / channels, i % channels);
+            let (x, y) = (pixel % width as usize, pixel / width as usize);
+
+            let threshold = match alpha {
+                Some(alpha) if alpha == channel => 32767,
+                _ => (BAYER[y % 4][x % 4] * 2 + 1) * 65535 / 32,
+            };
+
+            ((sample as u32 * 255 + threshold) / 65535).min(255) as u8
+        })
+        .collect();
+
+    Ok((pixels, color8))
+}
+
+pub fn has_transparency(rgba: &[u8]) -> bool {
+    samples(rgba)
+        .skip(3)
+        .step_by(4)
+        .any(|alpha| alpha < u16::MAX)
+}
+
+// The stack blur only exists for 8-bit and float samples, so 16-bit images are blurred as floats
+pub fn stack_blur(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    radius: u32,
+    channels: FastBlurChannels,
+) {
+    let mut floats: Vec<f32> = samples(buf).map(|sample| sample as f32).collect();
+
+    libblur::stack_blur_f32(
+        &mut floats,
+        width,
+        height,
+        radius,
+        channels,
+        libblur::ThreadingPolicy::Single,
+    );
+
+    for (bytes, value) in buf.chunks_exact_mut(2).zip(floats) {
+        let sample = value.round().clamp(0.0, u16::MAX as f32) as u16;
+        bytes.copy_from_slice(&sample.to_ne_bytes());
+    }
+}
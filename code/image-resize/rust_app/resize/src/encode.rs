@@ -0,0 +1,481 @@
+use fast_image_resize::{images::Image, PixelType, ResizeOptions, Resizer};
+use image::{
+    codecs::{
+        avif::AvifEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    ColorType, DynamicImage, GrayAlphaImage, GrayImage, ImageEncoder, RgbImage, RgbaImage,
+};
+use lambda_runtime::tracing;
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::CONFIG, jpeg, palette, ssim, ImageFormat, ImageQuality, Palette, ResizeParams,
+};
+
+// Encoder speed from 1 (slowest) to 10, faster speeds keep AVIF within the Lambda time budget
+const AVIF_SPEED: u8 = 8;
+
+// Below this quality artifacts get worse than a smaller image, so the size budget shrinks it instead
+const MIN_BUDGET_QUALITY: u8 = 10;
+const MAX_BUDGET_DOWNSCALES: u32 = 4;
+const BUDGET_DOWNSCALE_FACTOR: f32 = 0.75;
+
+const MIN_AUTO_QUALITY: u8 = 20;
+
+// oxipng preset from 0 (fastest) to 6, the timeout bounds the slower trials
+const PNG_OPTIMIZE_PRESET: u8 = 2;
+
+// PNG is lossless, so the quality only decides how much effort goes into compression
+fn get_png_quality(img_quality: &ImageQuality) -> CompressionType {
+    match CONFIG.png_quality.get(img_quality) {
+        0..=40 => CompressionType::Fast,
+        41..=80 => CompressionType::Default,
+        _ => CompressionType::Best,
+    }
+}
+
+// Palette PNGs are used for the lower quality levels unless a palette is requested or turned off
+pub fn get_png_palette(img_quality: &ImageQuality, params: &ResizeParams) -> Option<u16> {
+    match (params.palette, img_quality) {
+        (Some(Palette::Colors(colors)), _) => Some(colors),
+        (Some(Palette::Off), _) => None,
+        (None, ImageQuality::Low | ImageQuality::Medium) => Some(256),
+        _ => None,
+    }
+}
+
+// Searches the filter strategies and re-compresses the `best` quality PNGs losslessly, keeping the
+// encoder output if the optimizer fails or the time budget is disabled
+fn optimize_png(buffer: Vec<u8>, img_quality: &ImageQuality) -> Vec<u8> {
+    if !matches!(img_quality, ImageQuality::Best) || CONFIG.png_optimize_timeout == 0 {
+        return buffer;
+    }
+
+    let mut options = oxipng::Options::from_preset(PNG_OPTIMIZE_PRESET);
+    options.strip = oxipng::StripChunks::Safe;
+    options.optimize_alpha = true;
+    options.timeout = Some(Duration::from_millis(CONFIG.png_optimize_timeout));
+
+    let start = Instant::now();
+    match oxipng::optimize_from_memory(&buffer, &options) {
+        Ok(optimized) if optimized.len() < buffer.len() => {
+            tracing::info!(
+                "PNG optimized from {} to {} bytes in {:?}",
+                buffer.len(),
+                optimized.len(),
+                start.elapsed()
+            );
+            optimized
+        }
+        Ok(_) => buffer,
+        Err(err) => {
+            tracing::warn!("PNG optimization failed: {}", err);
+            buffer
+        }
+    }
+}
+
+fn get_jpeg_quality(img_quality: &ImageQuality) -> u8 {
+    CONFIG.jpeg_quality.get(img_quality)
+}
+
+fn get_avif_quality(img_quality: &ImageQuality) -> u8 {
+    CONFIG.avif_quality.get(img_quality)
+}
+
+// WebP stays lossless for the named quality levels unless WEBP_QUALITY maps them, an explicit
+// quality always switches to lossy
+fn get_webp_quality(img_quality: &ImageQuality) -> Option<u8> {
+    match (img_quality, &CONFIG.webp_quality) {
+        (ImageQuality::Value(value), _) => Some(*value),
+        (_, Some(table)) => Some(table.get(img_quality)),
+        (_, None) => None,
+    }
+}
+
+fn encode_lossy_webp(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    quality: u8,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let memory = match color {
+        ColorType::Rgb8 => webp::Encoder::from_rgb(buf, width, height).encode(quality as f32),
+        ColorType::Rgba8 => webp::Encoder::from_rgba(buf, width, height).encode(quality as f32),
+        ColorType::L8 => {
+            let rgb: Vec<u8> = buf.iter().flat_map(|l| [*l, *l, *l]).collect();
+            webp::Encoder::from_rgb(&rgb, width, height).encode(quality as f32)
+        }
+        _ => return Err(format!("Unsupported WebP color type {:?}", color).into()),
+    };
+
+    Ok(memory.to_vec())
+}
+
+pub fn encode(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: &ImageFormat,
+    quality: &ImageQuality,
+    params: &ResizeParams,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut buffer = Vec::new();
+
+    match format {
+        ImageFormat::Png => match get_png_palette(quality, params) {
+            Some(colors) => {
+                // The fast deflate mode barely compresses unfiltered palette indices
+                let compression = match get_png_quality(quality) {
+                    CompressionType::Best => png::Compression::Best,
+                    _ => png::Compression::Default,
+                };
+
+                buffer = palette::encode_png(
+                    buf,
+                    width,
+                    height,
+                    color,
+                    colors,
+                    palette::dither(params.dither),
+                    compression,
+                )?
+            }
+            None => PngEncoder::new_with_quality(
+                &mut buffer,
+                get_png_quality(quality),
+                FilterType::Adaptive,
+            )
+            .write_image(buf, width, height, color.into())?,
+        },
+
+        ImageFormat::Jpeg => {
+            buffer = jpeg::encode(
+                buf,
+                width,
+                height,
+                color,
+                get_jpeg_quality(quality),
+                params.progressive,
+                params.subsampling,
+            )?
+        }
+
+        ImageFormat::Webp => match get_webp_quality(quality) {
+            Some(quality) => buffer = encode_lossy_webp(buf, width, height, color, quality)?,
+            None => WebPEncoder::new_lossless(&mut buffer).write_image(
+                buf,
+                width,
+                height,
+                color.into(),
+            )?,
+        },
+
+        ImageFormat::Avif => {
+            AvifEncoder::new_with_speed_quality(&mut buffer, AVIF_SPEED, get_avif_quality(quality))
+                .write_image(buf, width, height, color.into())?
+        }
+
+        _ => return Err("Unknown format".into()),
+    };
+
+    if *format == ImageFormat::Png {
+        buffer = optimize_png(buffer, quality);
+    }
+
+    Ok(buffer)
+}
+
+pub struct SearchResult {
+    pub buffer: Vec<u8>,
+    pub quality: u8,
+}
+
+fn downscale(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    factor: f32,
+) -> Result<(Vec<u8>, u32, u32), Box<dyn std::error::Error>> {
+    let pixel_type = match color {
+        ColorType::L8 => PixelType::U8,
+        ColorType::La8 => PixelType::U8x2,
+        ColorType::Rgb8 => PixelType::U8x3,
+        ColorType::Rgba8 => PixelType::U8x4,
+        _ => return Err(format!("Unsupported color type {:?}", color).into()),
+    };
+
+    let new_width = ((width as f32 * factor) as u32).max(1);
+    let new_height = ((height as f32 * factor) as u32).max(1);
+
+    let src = Image::from_vec_u8(width, height, buf.to_vec(), pixel_type)?;
+    let mut dst = Image::new(new_width, new_height, pixel_type);
+
+    Resizer::new().resize(&src, &mut dst, &ResizeOptions::new())?;
+
+    Ok((dst.into_vec(), new_width, new_height))
+}
+
+// Finds the highest quality that fits into `max_bytes` with a binary search. When even the lowest
+// quality is too big the image is downscaled and searched again. If nothing fits, the smallest
+// attempt is returned.
+pub fn encode_within(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: &ImageFormat,
+    max_bytes: usize,
+    params: &ResizeParams,
+) -> Result<SearchResult, Box<dyn std::error::Error>> {
+    let max_quality = match format {
+        ImageFormat::Jpeg => get_jpeg_quality(&params.q),
+        ImageFormat::Avif => get_avif_quality(&params.q),
+        ImageFormat::Webp => get_webp_quality(&params.q).unwrap_or(100),
+        _ => return Err(format!("Size budget is not supported for {:?}", format).into()),
+    }
+    .max(MIN_BUDGET_QUALITY);
+
+    let mut pixels = buf.to_vec();
+    let (mut width, mut height) = (width, height);
+    let mut smallest: Option<SearchResult> = None;
+
+    for attempt in 0..=MAX_BUDGET_DOWNSCALES {
+        if attempt > 0 {
+            (pixels, width, height) =
+                downscale(&pixels, width, height, color, BUDGET_DOWNSCALE_FACTOR)?;
+        }
+
+        let (mut low, mut high) = (MIN_BUDGET_QUALITY, max_quality);
+        let mut best: Option<SearchResult> = None;
+
+        while low <= high {
+            let quality = low + (high - low) / 2;
+            let buffer = encode(
+                &pixels,
+                width,
+                height,
+                color,
+                format,
+                &ImageQuality::Value(quality),
+                params,
+            )?;
+
+            tracing::info!(
+                "Budget attempt {}x{} quality {}: {} bytes",
+                width,
+                height,
+                quality,
+                buffer.len()
+            );
+
+            let result = SearchResult { buffer, quality };
+
+            if result.buffer.len() <= max_bytes {
+                low = quality + 1;
+                best = Some(result);
+            } else {
+                high = quality - 1;
+
+                if smallest
+                    .as_ref()
+                    .is_none_or(|s| result.buffer.len() < s.buffer.len())
+                {
+                    smallest = Some(result);
+                }
+            }
+        }
+
+        if let Some(best) = best {
+            return Ok(best);
+        }
+    }
+
+    tracing::info!("No encoding fits into {} bytes", max_bytes);
+
+    smallest.ok_or_else(|| "Size budget search failed".into())
+}
+
+fn to_dynamic_image(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let buf = buf.to_vec();
+    let img = match color {
+        ColorType::L8 => GrayImage::from_raw(width, height, buf).map(DynamicImage::ImageLuma8),
+        ColorType::La8 => {
+            GrayAlphaImage::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        ColorType::Rgb8 => RgbImage::from_raw(width, height, buf).map(DynamicImage::ImageRgb8),
+        ColorType::Rgba8 => RgbaImage::from_raw(width, height, buf).map(DynamicImage::ImageRgba8),
+        _ => None,
+    };
+
+    img.ok_or_else(|| format!("Unsupported color type {:?}", color).into())
+}
+
+// Finds the lowest quality whose encoding stays structurally similar to the resized image. Each
+// candidate is decoded again and compared to the reference pixels with SSIM, so the choice only
+// depends on the image content.
+pub fn encode_auto(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: &ImageFormat,
+    params: &ResizeParams,
+) -> Result<SearchResult, Box<dyn std::error::Error>> {
+    let decode_format = match format {
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        ImageFormat::Webp => image::ImageFormat::WebP,
+        _ => {
+            // There is no decoder to measure the candidates with, so use the configured quality
+            tracing::info!("Auto quality is not supported for {:?}", format);
+
+            let quality = match format {
+                ImageFormat::Avif => get_avif_quality(&ImageQuality::Auto),
+                _ => get_jpeg_quality(&ImageQuality::Auto),
+            };
+            let buffer = encode(
+                buf,
+                width,
+                height,
+                color,
+                format,
+                &ImageQuality::Value(quality),
+                params,
+            )?;
+
+            return Ok(SearchResult { buffer, quality });
+        }
+    };
+
+    let reference = to_dynamic_image(buf, width, height, color)?;
+    let threshold = CONFIG.auto_quality_ssim;
+
+    let (mut low, mut high) = (MIN_AUTO_QUALITY, 100);
+    let mut best: Option<SearchResult> = None;
+
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let buffer = encode(
+            buf,
+            width,
+            height,
+            color,
+            format,
+            &ImageQuality::Value(quality),
+            params,
+        )?;
+
+        let candidate = image::load_from_memory_with_format(&buffer, decode_format)?;
+        let score = ssim::ssim(&reference, &candidate);
+
+        tracing::info!(
+            "Auto quality {}: ssim {:.4}, {} bytes",
+            quality,
+            score,
+            buffer.len()
+        );
+
+        if score >= threshold {
+            high = quality - 1;
+            best = Some(SearchResult { buffer, quality });
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(best),
+        None => {
+            let buffer = encode(
+                buf,
+                width,
+                height,
+                color,
+                format,
+                &ImageQuality::Value(100),
+                params,
+            )?;
+
+            Ok(SearchResult {
+                buffer,
+                quality: 100,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+
+    use super::encode_auto;
+    use crate::{ImageFormat, ResizeParams};
+
+    fn params() -> ResizeParams {
+        ResizeParams::from_url("https://example.com/a.jpg?q=auto").unwrap()
+    }
+
+    fn flat(width: u32, height: u32) -> Vec<u8> {
+        [200, 120, 40].repeat((width * height) as usize)
+    }
+
+    // Pseudo random noise, the hardest content for a lossy encoder
+    fn detailed(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height * 3)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn auto_quality_is_deterministic() {
+        let buf = detailed(64, 64);
+        let auto = || {
+            encode_auto(&buf, 64, 64, ColorType::Rgb8, &ImageFormat::Jpeg, &params()).unwrap()
+        };
+
+        let first = auto();
+        let second = auto();
+
+        assert_eq!(first.quality, second.quality);
+        assert_eq!(first.buffer, second.buffer);
+    }
+
+    #[test]
+    fn flat_image_gets_a_lower_quality_than_a_detailed_one() {
+        let flat = encode_auto(
+            &flat(64, 64),
+            64,
+            64,
+            ColorType::Rgb8,
+            &ImageFormat::Jpeg,
+            &params(),
+        )
+        .unwrap();
+        let detailed = encode_auto(
+            &detailed(64, 64),
+            64,
+            64,
+            ColorType::Rgb8,
+            &ImageFormat::Jpeg,
+            &params(),
+        )
+        .unwrap();
+
+        assert!(
+            flat.quality < detailed.quality,
+            "flat {}, detailed {}",
+            flat.quality,
+            detailed.quality
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use image::{ColorType, DynamicImage, GrayImage, RgbImage};
+use jpeg_decoder::{Decoder, PixelFormat};
+use jpeg_encoder::{Encoder, SamplingFactor};
+use lambda_runtime::tracing;
+
+use crate::{config::CONFIG, limits, orientation, utils, ResizeParams, TransformMode};
+
+// The scaled decode keeps at least twice the requested size, so the resize afterwards still
+// filters the image down instead of leaving the quality to the reduced IDCT
+const SHRINK_MARGIN: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsampling {
+    S444,
+    S422,
+    S420,
+}
+
+impl Subsampling {
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value {
+            "444" | "4:4:4" => Some(Subsampling::S444),
+            "422" | "4:2:2" => Some(Subsampling::S422),
+            "420" | "4:2:0" => Some(Subsampling::S420),
+            _ => None,
+        }
+    }
+
+    // Higher qualities keep more of the color resolution
+    fn for_quality(quality: u8) -> Self {
+        match quality {
+            0..=69 => Subsampling::S420,
+            70..=89 => Subsampling::S422,
+            _ => Subsampling::S444,
+        }
+    }
+}
+
+impl Display for Subsampling {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Subsampling::S444 => write!(f, "444"),
+            Subsampling::S422 => write!(f, "422"),
+            Subsampling::S420 => write!(f, "420"),
+        }
+    }
+}
+
+impl From<Subsampling> for SamplingFactor {
+    fn from(val: Subsampling) -> Self {
+        match val {
+            Subsampling::S444 => SamplingFactor::R_4_4_4,
+            Subsampling::S422 => SamplingFactor::R_4_2_2,
+            Subsampling::S420 => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+pub fn encode(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    quality: u8,
+    progressive: Option<bool>,
+    subsampling: Option<Subsampling>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let color_type = match color {
+        ColorType::Rgb8 => jpeg_encoder::ColorType::Rgb,
+        ColorType::L8 => jpeg_encoder::ColorType::Luma,
+        _ => return Err(format!("Unsupported JPEG color type {:?}", color).into()),
+    };
+
+    let mut buffer = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer, quality);
+
+    encoder.set_progressive(progressive.unwrap_or(CONFIG.jpeg_progressive));
+    encoder.set_sampling_factor(
+        subsampling
+            .unwrap_or_else(|| Subsampling::for_quality(quality))
+            .into(),
+    );
+    encoder.set_optimized_huffman_tables(true);
+
+    encoder.encode(buf, width.try_into()?, height.try_into()?, color_type)?;
+
+    Ok(buffer)
+}
+
+// Decodes large JPEGs at 1/2, 1/4 or 1/8 of their size in the DCT domain when the requested size
+// is much smaller, which skips most of the IDCT and color conversion work. Redactions, trims and
+// crops work on source pixels, so they keep the full decode, like CMYK and 12-bit JPEGs. `None`
+// falls back to the full decode, which also reports the errors.
+pub fn decode_scaled(buf: &[u8], params: &ResizeParams) -> Option<DynamicImage> {
+    let crop = matches!(params.t, TransformMode::Crop);
+    if crop || !params.redact.is_empty() || params.trim.is_some() {
+        return None;
+    }
+
+    let mut decoder = Decoder::new(Cursor::new(buf));
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+
+    if !matches!(info.pixel_format, PixelFormat::L8 | PixelFormat::RGB24)
+        || limits::check_pixels(info.width.into(), info.height.into()).is_err()
+    {
+        return None;
+    }
+
+    let (width, height) = match orientation::swaps_axes(orientation::read_orientation(buf)) {
+        true => (info.height, info.width),
+        false => (info.width, info.height),
+    };
+    let scale = utils::cover_scale(width as f32, height as f32, params) * SHRINK_MARGIN;
+    if scale >= 1.0 {
+        return None;
+    }
+
+    let requested = |size: u16| (size as f32 * scale).ceil() as u16;
+    let (scaled_width, scaled_height) = decoder
+        .scale(requested(info.width), requested(info.height))
+        .ok()?;
+    if scaled_width == info.width {
+        return None;
+    }
+
+    decoder.set_max_decoding_buffer_size(limits::max_alloc() as usize);
+    let pixels = decoder.decode().ok()?;
+
+    tracing::info!(
+        "Shrink on load {}x{} to {}x{}",
+        info.width,
+        info.height,
+        scaled_width,
+        scaled_height
+    );
+
+    let (width, height) = (scaled_width.into(), scaled_height.into());
+    match info.pixel_format {
+        PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        _ => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImageView;
+
+    use super::decode_scaled;
+    use crate::{resize_image, ImageFormat, ResizeParams};
+
+    // Green centre of 400x400 pixels on a red background
+    fn target(size: u16) -> Vec<u8> {
+        let centre = size as u32 / 2 - 200..size as u32 / 2 + 200;
+        let pixels: Vec<u8> = (0..size as u32 * size as u32)
+            .flat_map(|i| {
+                let (x, y) = (i % size as u32, i / size as u32);
+                match centre.contains(&x) && centre.contains(&y) {
+                    true => [0, 255, 0],
+                    false => [255, 0, 0],
+                }
+            })
+            .collect();
+
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 90)
+            .encode(&pixels, size, size, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        jpeg
+    }
+
+    fn params(query: &str) -> ResizeParams {
+        ResizeParams::from_url(&format!("https://example.com/a.jpg?{}", query)).unwrap()
+    }
+
+    #[test]
+    fn small_fits_decode_at_a_fraction() {
+        let img = decode_scaled(&target(1600), &params("w=100&h=100")).unwrap();
+
+        assert_eq!(img.dimensions(), (200, 200));
+    }
+
+    #[test]
+    fn close_sizes_decode_at_full_size() {
+        assert!(decode_scaled(&target(1600), &params("w=500&h=500")).is_none());
+    }
+
+    #[test]
+    fn crops_redactions_and_trims_decode_at_full_size() {
+        let jpeg = target(1600);
+
+        for query in [
+            "w=100&h=100&t=c",
+            "w=100&h=100&redact=0,0,10,10",
+            "w=100&h=100&trim=true",
+        ] {
+            assert!(decode_scaled(&jpeg, &params(query)).is_none(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn crops_keep_the_source_framing() {
+        let resized = resize_image(
+            &target(1600),
+            params("w=200&h=200&t=c&f=png&q=h"),
+            ImageFormat::Jpeg,
+            vec![ImageFormat::Png],
+        )
+        .unwrap();
+        let out = image::load_from_memory(&resized.buffer).unwrap().to_rgb8();
+
+        // The 200x200 centre cut lies within the green square
+        for pixel in [out.get_pixel(0, 0), out.get_pixel(199, 199)] {
+            assert!(pixel[1] > 200 && pixel[0] < 50, "{:?}", pixel);
+        }
+    }
+}
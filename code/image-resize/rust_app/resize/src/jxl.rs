@@ -0,0 +1,59 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageBuffer};
+use jxl_oxide::{AllocTracker, EnumColourEncoding, JxlImage, RenderingIntent};
+
+use crate::limits;
+
+// Renders the first keyframe with its orientation applied. Samples deeper than 8 bits are kept
+// as 16-bit, matching what the `image` crate produces for 16-bit PNGs.
+pub fn decode(buf: &[u8]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut image = JxlImage::builder()
+        .alloc_tracker(AllocTracker::with_limit(limits::max_alloc() as usize))
+        .read(Cursor::new(buf))
+        .map_err(|e| format!("Invalid JPEG XL: {}", e))?;
+
+    // Frames are only decoded when rendered, so the header and frame count are checked first
+    limits::check_pixels(image.width(), image.height())?;
+    limits::check_frames(image.num_loaded_keyframes() as u32)?;
+
+    // CMYK masters are converted to sRGB by the decoder
+    if image.pixel_format().has_black() {
+        image.request_color_encoding(EnumColourEncoding::srgb(RenderingIntent::Relative));
+    }
+
+    let high_bit_depth = image.image_header().metadata.bit_depth.bits_per_sample() > 8;
+
+    let render = image
+        .render_frame(0)
+        .map_err(|e| format!("JPEG XL render failed: {}", e))?;
+    let mut stream = render.stream();
+    let (width, height, channels) = (stream.width(), stream.height(), stream.channels());
+    let len = width as usize * height as usize * channels as usize;
+
+    let img = if high_bit_depth {
+        let mut samples = vec![0u16; len];
+        stream.write_to_buffer(&mut samples);
+
+        match channels {
+            1 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma16),
+            2 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA16),
+            3 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16),
+            4 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16),
+            _ => None,
+        }
+    } else {
+        let mut samples = vec![0u8; len];
+        stream.write_to_buffer(&mut samples);
+
+        match channels {
+            1 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma8),
+            2 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA8),
+            3 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8),
+            4 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8),
+            _ => None,
+        }
+    };
+
+    img.ok_or_else(|| format!("Unsupported JPEG XL channel count {}", channels).into())
+}
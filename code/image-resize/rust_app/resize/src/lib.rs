@@ -1,23 +1,47 @@
 use config::CONFIG;
 use fast_image_resize::{images::Image, CpuExtensions, IntoImageView, ResizeOptions, Resizer};
-use image::{
-    codecs::{
-        jpeg::JpegEncoder,
-        png::{CompressionType, FilterType, PngEncoder},
-        webp::WebPEncoder,
-    },
-    ColorType, ImageEncoder,
-};
-use image::{DynamicImage, GenericImageView};
+use image::{ColorType, DynamicImage, GenericImageView};
 use lambda_runtime::tracing;
 use libblur::FastBlurChannels;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 use std::{collections::HashMap, io::Cursor, time::Instant};
 use url::Url;
 use utils::limit;
 
+pub use canvas::Border;
+pub use color::Color;
+pub use jpeg::Subsampling;
+pub use limits::{check_input, max_input_bytes, LimitError};
+pub use mask::Radius;
+pub use palette::Palette;
+pub use redact::{RedactMode, Region, RegionError};
+pub use sniff::detect_format;
+pub use svg::sanitize as sanitize_svg;
+pub use tiff_page::PageError;
+
+mod alpha;
+mod canvas;
+mod color;
 mod config;
+mod depth;
+mod encode;
+mod jpeg;
+mod jxl;
+mod limits;
+mod linear;
+mod mask;
+mod orientation;
+mod palette;
+#[cfg(feature = "raw")]
+mod raw;
+mod redact;
+mod sniff;
+mod ssim;
+mod svg;
+mod tiff_page;
+mod trim;
 mod utils;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -26,14 +50,31 @@ pub enum ImageQuality {
     Medium,
     High,
     Best,
+    Value(u8), // Explicit quality between 1 and 100
+    Auto,      // Lowest quality that stays perceptually close to the resized image
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ImageFormat {
     Png,
     Jpeg,
     Webp,
     Gif,
+    Avif,
+    Tiff, // Input only, like the formats below
+    Bmp,
+    Ico,
+    Qoi,
+    Svg,
+    Jxl,
+    // Camera RAW and DNG, only detected with the `raw` feature
+    Raw,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Auto,                // Smallest of the formats the client accepts
+    Format(ImageFormat), // Explicit format regardless of the Accept header
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +92,15 @@ impl Display for TransformMode {
     }
 }
 
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            OutputFormat::Auto => write!(f, "auto"),
+            OutputFormat::Format(format) => write!(f, "{}", format.extension()),
+        }
+    }
+}
+
 impl Display for ImageQuality {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
@@ -58,52 +108,187 @@ impl Display for ImageQuality {
             ImageQuality::Medium => write!(f, "m"),
             ImageQuality::High => write!(f, "h"),
             ImageQuality::Best => write!(f, "b"),
+            ImageQuality::Value(value) => write!(f, "{}", value),
+            ImageQuality::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl ImageQuality {
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value {
+            "low" | "l" => Some(ImageQuality::Low),
+            "medium" | "m" => Some(ImageQuality::Medium),
+            "high" | "h" => Some(ImageQuality::High),
+            "best" | "b" => Some(ImageQuality::Best),
+            "auto" | "a" => Some(ImageQuality::Auto),
+            _ => value
+                .parse::<u8>()
+                .ok()
+                .map(|value| ImageQuality::Value(value.clamp(1, 100))),
         }
     }
 }
 
 impl ImageFormat {
     pub fn from_extension(ext: &str) -> Option<Self> {
-        match ext {
+        match ext.to_lowercase().as_str() {
             "png" => Some(Self::Png),
             "jpg" | "jpeg" => Some(Self::Jpeg),
             "webp" => Some(Self::Webp),
             "gif" => Some(Self::Gif),
+            "avif" => Some(Self::Avif),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "bmp" => Some(Self::Bmp),
+            "ico" => Some(Self::Ico),
+            "qoi" => Some(Self::Qoi),
+            "svg" => Some(Self::Svg),
+            "jxl" => Some(Self::Jxl),
+            #[cfg(feature = "raw")]
+            "dng" | "cr2" | "nef" | "nrw" | "arw" | "orf" | "rw2" | "raf" | "pef" | "srw" => {
+                Some(Self::Raw)
+            }
             _ => None,
         }
     }
-}
 
-impl From<ImageFormat> for image::ImageFormat {
-    fn from(val: ImageFormat) -> Self {
-        match val {
-            ImageFormat::Png => image::ImageFormat::Png,
-            ImageFormat::Gif => image::ImageFormat::Gif,
-            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
-            ImageFormat::Webp => image::ImageFormat::WebP,
+    // Ignores parameters such as `; charset=binary`
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next()?.trim().to_lowercase();
+
+        match media_type.as_str() {
+            "image/png" => Some(Self::Png),
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/webp" => Some(Self::Webp),
+            "image/gif" => Some(Self::Gif),
+            "image/avif" => Some(Self::Avif),
+            "image/tiff" => Some(Self::Tiff),
+            "image/bmp" | "image/x-bmp" => Some(Self::Bmp),
+            "image/x-icon" | "image/vnd.microsoft.icon" => Some(Self::Ico),
+            "image/qoi" | "image/x-qoi" => Some(Self::Qoi),
+            "image/svg+xml" => Some(Self::Svg),
+            "image/jxl" => Some(Self::Jxl),
+            #[cfg(feature = "raw")]
+            "image/x-adobe-dng" | "image/dng" | "image/x-canon-cr2" | "image/x-nikon-nef"
+            | "image/x-sony-arw" | "image/x-olympus-orf" | "image/x-panasonic-rw2"
+            | "image/x-fuji-raf" | "image/x-pentax-pef" | "image/x-samsung-srw" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Tiff => "image/tiff",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Ico => "image/x-icon",
+            ImageFormat::Qoi => "image/qoi",
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Jxl => "image/jxl",
+            ImageFormat::Raw => "image/x-adobe-dng",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Ico => "ico",
+            ImageFormat::Qoi => "qoi",
+            ImageFormat::Svg => "svg",
+            ImageFormat::Jxl => "jxl",
+            ImageFormat::Raw => "dng",
         }
     }
+
+    // Formats that are encoded for the web, the others are only decoded
+    pub fn is_web(&self) -> bool {
+        matches!(
+            self,
+            ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Webp
+                | ImageFormat::Gif
+                | ImageFormat::Avif
+        )
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        !matches!(self, ImageFormat::Jpeg)
+    }
+
+    // AVIF is only encoded, the `image` crate is built without its decoder
+    pub fn is_decodable(&self) -> bool {
+        !matches!(self, ImageFormat::Avif)
+    }
+
+    // Formats with a lossy encoder whose quality can be searched for a size budget
+    pub fn has_quality(&self) -> bool {
+        matches!(self, ImageFormat::Jpeg | ImageFormat::Webp | ImageFormat::Avif)
+    }
 }
 
-impl From<String> for ImageFormat {
-    fn from(val: String) -> Self {
-        match val.to_lowercase().as_str() {
-            "png" | "image/png" => Self::Png,
-            "jpg" | "jpeg" | "image/jpeg" => Self::Jpeg,
-            "webp" | "image/webp" => Self::Webp,
-            _ => Self::Jpeg,
+impl TryFrom<ImageFormat> for image::ImageFormat {
+    type Error = String;
+
+    fn try_from(val: ImageFormat) -> Result<Self, Self::Error> {
+        match val {
+            ImageFormat::Png => Ok(image::ImageFormat::Png),
+            ImageFormat::Gif => Ok(image::ImageFormat::Gif),
+            ImageFormat::Jpeg => Ok(image::ImageFormat::Jpeg),
+            ImageFormat::Webp => Ok(image::ImageFormat::WebP),
+            ImageFormat::Avif => Ok(image::ImageFormat::Avif),
+            ImageFormat::Tiff => Ok(image::ImageFormat::Tiff),
+            ImageFormat::Bmp => Ok(image::ImageFormat::Bmp),
+            ImageFormat::Ico => Ok(image::ImageFormat::Ico),
+            ImageFormat::Qoi => Ok(image::ImageFormat::Qoi),
+            ImageFormat::Svg => Err("SVG is not a raster format".to_string()),
+            ImageFormat::Jxl => Err("JPEG XL is not decoded by the image crate".to_string()),
+            ImageFormat::Raw => Err("Camera RAW is not decoded by the image crate".to_string()),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ResizeParams {
-    pub t: TransformMode, // Transformation mode: "fit" or "crop"
-    pub w: u32,           // Width
-    pub h: Option<u32>,   // Height
-    pub o: bool,          // Get original image
-    pub b: u32,           // Blur level
-    pub q: ImageQuality,  // Image quality
+    pub t: TransformMode,                 // Transformation mode: "fit" or "crop"
+    pub w: u32,                           // Width
+    pub h: Option<u32>,                   // Height
+    pub o: bool,                          // Get original image
+    pub b: u32,                           // Blur level
+    pub q: ImageQuality,                  // Image quality
+    pub radius: Option<Radius>,           // Corner radius in pixels or "max" for a circle
+    pub bg: Option<Color>,                // Background color used when flattening alpha
+    pub border: Option<Border>,           // Frame drawn around the resized image
+    pub pad: u32,                         // Margin added outside the border
+    pub trim: Option<u8>,                 // Color-distance threshold for trimming uniform borders
+    pub redact: Vec<Region>,              // Source image regions to obscure
+    pub redact_mode: RedactMode,          // How the regions are obscured: "blur" or "pixelate"
+    pub progressive: Option<bool>,        // Progressive JPEG encoding
+    pub subsampling: Option<Subsampling>, // JPEG chroma subsampling: 444, 422 or 420
+    pub maxbytes: Option<usize>,          // Output size budget in bytes
+    pub f: Option<OutputFormat>,          // Output format: "auto" or an explicit image format
+    pub palette: Option<Palette>,         // PNG/GIF palette size, or "off" to keep truecolor PNGs
+    pub dither: Option<bool>,             // Floyd-Steinberg dithering of palette images
+    pub linear: Option<bool>,             // Resampling in linear light instead of sRGB
+    pub page: u32,                        // Page of a multi-page TIFF, counted from 0
+    pub sized: bool,                      // Whether `w` or `h` was requested
+}
+
+#[derive(Debug, Clone)]
+pub struct ResizedImage {
+    pub buffer: Vec<u8>,
+    pub format: ImageFormat,
+    pub quality: Option<u8>, // Encoder quality chosen to fit the size budget
 }
 
 impl ResizeParams {
@@ -137,33 +322,131 @@ impl ResizeParams {
             .map(|b| limit(b.into(), 0, 50, None))
             .unwrap_or(0);
 
-        let q = match search_params.get("q").map(String::as_str) {
-            Some("low") | Some("l") => ImageQuality::Low,
-            Some("medium") | Some("m") => ImageQuality::Medium,
-            Some("high") | Some("h") => ImageQuality::High,
-            Some("best") | Some("b") => ImageQuality::Best,
-            _ => CONFIG.default_quality.clone(),
+        let q = search_params
+            .get("q")
+            .and_then(|q| ImageQuality::from_param(q))
+            .unwrap_or_else(|| CONFIG.default_quality.clone());
+
+        let radius = search_params
+            .get("radius")
+            .and_then(|r| Radius::from_param(r));
+
+        let bg = search_params.get("bg").and_then(|bg| Color::from_hex(bg));
+
+        let border = search_params
+            .get("border")
+            .and_then(|border| Border::from_param(border));
+
+        let pad = search_params
+            .get("pad")
+            .and_then(|pad| canvas::parse_padding(pad))
+            .unwrap_or(0);
+
+        let trim = search_params
+            .get("trim")
+            .and_then(|trim| trim::parse_threshold(trim));
+
+        let redact = search_params
+            .get("redact")
+            .map(|redact| redact::parse_regions(redact))
+            .transpose()?
+            .unwrap_or_default();
+
+        let redact_mode = search_params
+            .get("redact_mode")
+            .and_then(|mode| redact::parse_mode(mode))
+            .unwrap_or(RedactMode::Blur);
+
+        let progressive = search_params
+            .get("progressive")
+            .and_then(|progressive| progressive.parse::<bool>().ok());
+
+        let subsampling = search_params
+            .get("subsampling")
+            .and_then(|subsampling| Subsampling::from_param(subsampling));
+
+        let maxbytes = search_params
+            .get("maxbytes")
+            .and_then(|maxbytes| maxbytes.parse::<usize>().ok())
+            .filter(|maxbytes| *maxbytes > 0);
+
+        let f = match search_params.get("f").map(String::as_str) {
+            Some("auto") => Some(OutputFormat::Auto),
+            Some(f) => ImageFormat::from_extension(f)
+                .filter(ImageFormat::is_web)
+                .map(OutputFormat::Format),
+            None => None,
         };
 
-        Ok(ResizeParams { t, w, h, o, b, q })
+        let palette = search_params
+            .get("palette")
+            .and_then(|palette| Palette::from_param(palette));
+
+        let dither = search_params
+            .get("dither")
+            .and_then(|dither| dither.parse::<bool>().ok());
+
+        let linear = search_params
+            .get("linear")
+            .and_then(|linear| linear.parse::<bool>().ok());
+
+        let page = search_params
+            .get("page")
+            .and_then(|page| tiff_page::parse_page(page))
+            .unwrap_or(0);
+
+        let sized = search_params.contains_key("w") || search_params.contains_key("h");
+
+        Ok(ResizeParams {
+            t,
+            w,
+            h,
+            o,
+            b,
+            q,
+            radius,
+            bg,
+            border,
+            pad,
+            trim,
+            redact,
+            redact_mode,
+            progressive,
+            subsampling,
+            maxbytes,
+            f,
+            palette,
+            dither,
+            linear,
+            page,
+            sized,
+        })
     }
 }
 
-fn get_png_quality(img_quality: ImageQuality) -> CompressionType {
-    match img_quality {
-        ImageQuality::Low => CompressionType::Default,
-        ImageQuality::Medium => CompressionType::Default,
-        ImageQuality::High => CompressionType::Fast,
-        ImageQuality::Best => CompressionType::Best,
+fn decode(
+    img_buf: &[u8],
+    format: &ImageFormat,
+    params: &ResizeParams,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    if *format == ImageFormat::Jpeg {
+        if let Some(img) = jpeg::decode_scaled(img_buf, params) {
+            return Ok(img);
+        }
     }
-}
 
-fn get_jpeg_quality(img_quality: ImageQuality) -> u8 {
-    match img_quality {
-        ImageQuality::Low => 25,
-        ImageQuality::Medium => 50,
-        ImageQuality::High => 75,
-        ImageQuality::Best => 100,
+    match (format, params.page) {
+        (ImageFormat::Svg, _) => svg::rasterize(img_buf, params),
+        (ImageFormat::Jxl, _) => jxl::decode(img_buf),
+        #[cfg(feature = "raw")]
+        (ImageFormat::Raw, _) => raw::decode(img_buf, params),
+        (ImageFormat::Tiff, page) if page > 0 => tiff_page::decode_page(img_buf, page),
+        _ => {
+            let mut reader = image::ImageReader::new(Cursor::new(img_buf));
+            reader.set_format(format.clone().try_into()?);
+
+            limits::decode(reader)
+        }
     }
 }
 
@@ -171,34 +454,50 @@ pub fn resize_image(
     img_buf: &[u8],
     params: ResizeParams,
     format: ImageFormat,
-    target_format: Option<ImageFormat>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    target_formats: Vec<ImageFormat>,
+) -> Result<ResizedImage, Box<dyn std::error::Error>> {
+    limits::check_input(img_buf.len())?;
+
     let start = Instant::now();
-    let mut reader = image::ImageReader::new(Cursor::new(img_buf));
-    reader.set_format(format.clone().into());
 
-    let img: DynamicImage = match reader.decode() {
+    let img: DynamicImage = match decode(img_buf, &format, &params) {
         Ok(img) => img,
-        Err(_) => {
-            // Try to detect the format
-            let detected_format = match imghdr::from_bytes(img_buf) {
-                Some(imghdr::Type::Jpeg) => ImageFormat::Jpeg,
-                Some(imghdr::Type::Png) => ImageFormat::Png,
-                Some(imghdr::Type::Webp) => ImageFormat::Webp,
-                Some(imghdr::Type::Gif) => ImageFormat::Gif,
-                _ => return Err("Unknown file format".into()),
+        Err(err) => {
+            // The given format may come from a misleading extension, so retry with the sniffed one
+            let detected_format = match sniff::sniff(img_buf) {
+                Some(detected_format) if detected_format != format => detected_format,
+                _ => return Err(err),
             };
 
-            let mut reader = image::ImageReader::new(Cursor::new(img_buf));
-            reader.set_format(detected_format.into());
-
-            reader.decode()?
+            decode(img_buf, &detected_format, &params)?
         }
     };
 
     let duration = start.elapsed();
     tracing::info!("Load time: {:?}", duration);
 
+    let mut img = match orientation::read_orientation(img_buf) {
+        Some(orientation) => orientation::apply_orientation(img, orientation),
+        None => img,
+    };
+
+    if !params.redact.is_empty() {
+        let start = Instant::now();
+        redact::redact(&mut img, &params.redact, params.redact_mode)?;
+        tracing::info!("Redact time: {:?}", start.elapsed());
+    }
+
+    let img = match params.trim {
+        Some(threshold) => {
+            let start = Instant::now();
+            let img = trim::trim(img, threshold);
+            tracing::info!("Trim time: {:?}", start.elapsed());
+
+            img
+        }
+        None => img,
+    };
+
     let (width, height) = img.dimensions();
 
     let start = Instant::now();
@@ -214,19 +513,35 @@ pub fn resize_image(
         TransformMode::Crop => params.h.unwrap_or(CONFIG.default_height),
     };
 
-    let format_to_use = target_format.unwrap_or(format);
+    // A fit of a very tall image by its width alone can ask for a huge height, and the border and
+    // padding are drawn around the resized image
+    let offset = canvas::offset(params.border, params.pad) * 2;
+    limits::check_output(
+        params.w.saturating_add(offset),
+        img_height.saturating_add(offset),
+    )?;
 
-    match format_to_use {
-        ImageFormat::Gif => resize_multi_pages(img, params.w, img_height, params.t),
-        _ => resize_single_page(
-            img,
-            format_to_use,
-            params.w,
-            img_height,
-            params.t,
-            params.b,
-            params.q,
-        ),
+    // Without a target the source format is kept, otherwise the smallest encoding among the
+    // targets is used. GIF is only produced when it is the only target. Sources that are not
+    // encoded, such as TIFF scans, SVGs or JPEG XL masters, become a JPEG or a PNG when they are
+    // transparent.
+    let formats = match target_formats.as_slice() {
+        [] if format.is_web() => vec![format],
+        [] => vec![ImageFormat::Jpeg, ImageFormat::Png],
+        [_] => target_formats,
+        _ => target_formats
+            .into_iter()
+            .filter(|f| *f != ImageFormat::Gif)
+            .collect(),
+    };
+
+    match formats.as_slice() {
+        [ImageFormat::Gif] => Ok(ResizedImage {
+            buffer: resize_multi_pages(img, params.w, img_height, &params)?,
+            format: ImageFormat::Gif,
+            quality: None,
+        }),
+        _ => resize_single_page(img, &formats, params.w, img_height, &params),
     }
 }
 
@@ -235,35 +550,89 @@ fn resize_multi_pages(
     img: DynamicImage,
     width: u32,
     height: u32,
-    transform: TransformMode,
+    params: &ResizeParams,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     tracing::info!("Resizing multi pages image: {}, {}", width, height);
 
-    let resized_img = match transform {
+    let resized_img = match params.t {
         TransformMode::Fit => {
             img.resize_to_fill(width, height, image::imageops::FilterType::Lanczos3)
         }
         TransformMode::Crop => img.resize(width, height, image::imageops::FilterType::Lanczos3),
     };
 
-    let mut buf = Cursor::new(Vec::new());
+    // GIF is always indexed, so turning the palette off only falls back to the full 256 colors
+    let colors = match params.palette {
+        Some(Palette::Colors(colors)) => colors,
+        _ => 256,
+    };
+
+    let start = Instant::now();
+    let mut resized_img = resized_img.to_rgba8();
+
+    // The corners become the transparent entry of the palette
+    if let Some(radius) = params.radius {
+        let (width, height) = resized_img.dimensions();
+        mask::apply_radius(&mut resized_img, width, height, radius);
+    }
+
+    let (mut width, mut height) = resized_img.dimensions();
+    let mut pixels = resized_img.into_raw();
+
+    if params.border.is_some() || params.pad > 0 {
+        (pixels, width, height) = canvas::extend(
+            &pixels,
+            width,
+            height,
+            4,
+            params.border,
+            params.pad,
+            params.bg.unwrap_or(CONFIG.default_background),
+        );
+    }
 
-    resized_img.write_to(&mut buf, ImageFormat::Gif.into())?;
+    let buffer = palette::encode_gif(
+        &pixels,
+        width,
+        height,
+        ColorType::Rgba8,
+        colors,
+        palette::dither(params.dither),
+    )?;
+    tracing::info!("Quantize time: {:?}", start.elapsed());
 
-    Ok(buf.into_inner())
+    Ok(buffer)
 }
 
 fn resize_single_page(
     img: DynamicImage,
-    format: ImageFormat,
+    formats: &[ImageFormat],
     width: u32,
     height: u32,
-    transform: TransformMode,
-    blur: u32,
-    quality: ImageQuality,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    params: &ResizeParams,
+) -> Result<ResizedImage, Box<dyn std::error::Error>> {
     let mut resizer = Resizer::new();
 
+    // Every decoded color type is normalized to L, RGB or RGBA, the layouts the blur, masks and
+    // encoders work on. Transparent images keep their alpha so they can be flattened, the corner
+    // mask also needs an alpha channel to draw into. Samples deeper than 8 bits are kept as 16-bit
+    // and grayscale stays single channel, unless a mask, border or padding is drawn, which only
+    // exist for 8-bit color.
+    let decoded = img.color();
+    let drawn = params.radius.is_some() || params.border.is_some() || params.pad > 0;
+    let alpha = decoded.has_alpha() || params.radius.is_some();
+    let gray = !decoded.has_color() && !drawn;
+    let high_depth = decoded.bytes_per_pixel() > decoded.channel_count() && !drawn;
+
+    let img = match (high_depth, alpha, gray) {
+        (false, true, _) => DynamicImage::ImageRgba8(img.into_rgba8()),
+        (false, false, true) => DynamicImage::ImageLuma8(img.into_luma8()),
+        (false, false, false) => DynamicImage::ImageRgb8(img.into_rgb8()),
+        (true, true, _) => DynamicImage::ImageRgba16(img.into_rgba16()),
+        (true, false, true) => DynamicImage::ImageLuma16(img.into_luma16()),
+        (true, false, false) => DynamicImage::ImageRgb16(img.into_rgb16()),
+    };
+
     let (origin_width, origin_height) = img.dimensions();
     let color = img.color();
 
@@ -272,88 +641,609 @@ fn resize_single_page(
         resizer.set_cpu_extensions(CpuExtensions::Avx2);
     }
 
-    let mut final_image = Image::new(width, height, img.pixel_type().unwrap());
+    let pixel_type = img.pixel_type().unwrap();
+    let mut final_image = Image::new(width, height, pixel_type);
 
     let start = Instant::now();
-    match transform {
-        TransformMode::Fit => resizer
-            .resize(
-                &img,
-                &mut final_image,
-                &ResizeOptions::new().fit_into_destination(Some((0.5, 0.5))),
-            )
-            .unwrap(),
+    // The resizer premultiplies alpha before resampling and divides it back afterwards, so the
+    // color of transparent pixels does not bleed into the edges
+    let options = match params.t {
+        TransformMode::Fit => ResizeOptions::new().fit_into_destination(Some((0.5, 0.5))),
         TransformMode::Crop => {
-            let left = (origin_width - width) / 2;
-            let top = (origin_height - height) / 2;
-            resizer
-                .resize(
-                    &img,
-                    &mut final_image,
-                    &ResizeOptions::new().crop(
-                        left.into(),
-                        top.into(),
-                        width.into(),
-                        height.into(),
-                    ),
-                )
-                .unwrap();
+            // Sources smaller than the crop, e.g. after a trim, are cut at the output aspect ratio
+            // and enlarged, so the box never leaves the image
+            let (origin_width, origin_height) = (origin_width as f64, origin_height as f64);
+            let scale = (origin_width / width as f64)
+                .min(origin_height / height as f64)
+                .min(1.0);
+            let crop_width = (width as f64 * scale).min(origin_width);
+            let crop_height = (height as f64 * scale).min(origin_height);
+            let left = (origin_width - crop_width) / 2.0;
+            let top = (origin_height - crop_height) / 2.0;
+            ResizeOptions::new().crop(left, top, crop_width, crop_height)
         } // _ => return Err("Invalid transformation mode".into()),
     };
+
+    if linear::enabled(params.linear) {
+        linear::resize(&mut resizer, &img, &mut final_image, &options)?;
+    } else {
+        resizer.resize(&img, &mut final_image, &options)?;
+    }
     let duration = start.elapsed();
     tracing::info!("Resize time: {:?}", duration);
 
-    let final_image_buf = final_image.buffer_mut();
+    let mut final_image_buf = final_image.into_vec();
 
     let start = Instant::now();
     // Apply blur if specified
 
-    let channel_count = match img.color() {
-        ColorType::Rgba8 | ColorType::Rgba16 | ColorType::Rgba32F => 4,
-        _ => 3,
+    let channel_count = color.channel_count() as u32;
+    let channels = match channel_count {
+        1 => FastBlurChannels::Plane,
+        4 => FastBlurChannels::Channels4,
+        _ => FastBlurChannels::Channels3,
     };
 
-    if blur > 0 {
+    let premultiplied = params.b > 0 && color.has_alpha();
+    if premultiplied {
+        alpha::premultiply(&mut final_image_buf, width, height, pixel_type)?;
+    }
+
+    if params.b > 0 && depth::is_high_depth(color) {
+        depth::stack_blur(&mut final_image_buf, width, height, params.b, channels);
+    } else if params.b > 0 {
         libblur::stack_blur(
-            final_image_buf,
+            &mut final_image_buf,
             width * channel_count,
             width,
             height,
-            blur,
-            match channel_count {
-                4 => FastBlurChannels::Channels4,
-                _ => FastBlurChannels::Channels3,
-            },
+            params.b,
+            channels,
             libblur::ThreadingPolicy::Single,
         );
     };
+
+    if premultiplied {
+        alpha::unpremultiply(&mut final_image_buf, width, height, pixel_type)?;
+    }
     let duration = start.elapsed();
     tracing::info!("Blur time: {:?}", duration);
 
-    let mut buffer = Vec::new();
+    if let Some(radius) = params.radius {
+        mask::apply_radius(&mut final_image_buf, width, height, radius);
+    }
 
-    let start = Instant::now();
-    match format {
-        ImageFormat::Png => PngEncoder::new_with_quality(
-            &mut buffer,
-            get_png_quality(quality),
-            FilterType::Adaptive,
-        )
-        .write_image(final_image_buf, width, height, color.into())
-        .unwrap(),
+    let (width, height) = if params.border.is_some() || params.pad > 0 {
+        let (buf, width, height) = canvas::extend(
+            &final_image_buf,
+            width,
+            height,
+            channel_count as usize,
+            params.border,
+            params.pad,
+            params.bg.unwrap_or(CONFIG.default_background),
+        );
+        final_image_buf = buf;
 
-        ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut buffer, get_jpeg_quality(quality))
-            .write_image(final_image_buf, width, height, color.into())
-            .unwrap(),
+        (width, height)
+    } else {
+        (width, height)
+    };
 
-        ImageFormat::Webp => WebPEncoder::new_lossless(&mut buffer)
-            .write_image(final_image_buf, width, height, color.into())
-            .unwrap(),
+    // Formats that would lose the transparency are only used when nothing else is accepted
+    let needs_alpha = match color {
+        ColorType::Rgba8 => color::has_transparency(&final_image_buf),
+        ColorType::Rgba16 => depth::has_transparency(&final_image_buf),
+        _ => false,
+    };
+    let alpha_formats: Vec<&ImageFormat> = formats.iter().filter(|f| f.has_alpha()).collect();
+    let candidates = if needs_alpha && !alpha_formats.is_empty() {
+        alpha_formats
+    } else {
+        formats.iter().collect()
+    };
+
+    let mut smallest: Option<ResizedImage> = None;
+
+    for format in candidates {
+        let resized_image =
+            encode_output(&final_image_buf, width, height, color, format.clone(), params)?;
+
+        tracing::info!(
+            "Encoded {:?}: {} bytes",
+            resized_image.format,
+            resized_image.buffer.len()
+        );
+
+        if smallest
+            .as_ref()
+            .is_none_or(|s| resized_image.buffer.len() < s.buffer.len())
+        {
+            smallest = Some(resized_image);
+        }
+    }
+
+    smallest.ok_or_else(|| "No output format".into())
+}
+
+fn encode_output(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: ImageFormat,
+    params: &ResizeParams,
+) -> Result<ResizedImage, Box<dyn std::error::Error>> {
+    let mut format = format;
+    let mut color = color;
+    let mut final_image_buf = Cow::Borrowed(pixels);
 
-        _ => return Err("Unknown format".into()),
+    // JPEG cannot carry the corner mask, so keep it in PNG unless a `bg` to bake it onto is given
+    if params.radius.is_some() && params.bg.is_none() && !format.has_alpha() {
+        format = ImageFormat::Png;
+    }
+
+    // Only truecolor PNG stores 16-bit samples, the other encoders get them dithered to 8-bit
+    let keeps_depth =
+        format == ImageFormat::Png && encode::get_png_palette(&params.q, params).is_none();
+    if depth::is_high_depth(color) && !keeps_depth {
+        let (buf, color8) = depth::to_8bit(&final_image_buf, width, color)?;
+        final_image_buf = Cow::Owned(buf);
+        color = color8;
+    }
+
+    if color.has_alpha() && !format.has_alpha() {
+        tracing::info!("Flattening alpha for {:?} output", format);
+
+        final_image_buf = Cow::Owned(color::flatten(
+            &final_image_buf,
+            &params.bg.unwrap_or(CONFIG.default_background),
+        ));
+        color = ColorType::Rgb8;
+    }
+
+    let start = Instant::now();
+    let resized_image = match (params.maxbytes, &params.q) {
+        (Some(max_bytes), _) if format.has_quality() => {
+            let result = encode::encode_within(
+                &final_image_buf,
+                width,
+                height,
+                color,
+                &format,
+                max_bytes,
+                params,
+            )?;
+
+            ResizedImage {
+                buffer: result.buffer,
+                format,
+                quality: Some(result.quality),
+            }
+        }
+        (None, ImageQuality::Auto) if format.has_quality() => {
+            let result =
+                encode::encode_auto(&final_image_buf, width, height, color, &format, params)?;
+
+            ResizedImage {
+                buffer: result.buffer,
+                format,
+                quality: Some(result.quality),
+            }
+        }
+        _ => ResizedImage {
+            buffer: encode::encode(
+                &final_image_buf,
+                width,
+                height,
+                color,
+                &format,
+                &params.q,
+                params,
+            )?,
+            format,
+            quality: None,
+        },
     };
     let duration = start.elapsed();
     tracing::info!("Encode time: {:?}", duration);
 
-    Ok(buffer)
-}
\ No newline at end of file
+    Ok(resized_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{
+        DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, LumaA, Rgb, Rgb32FImage, RgbImage,
+        Rgba,
+    };
+
+    use super::*;
+
+    fn params(query: &str) -> ResizeParams {
+        ResizeParams::from_url(&format!("https://example.com/a.png?{}", query)).unwrap()
+    }
+
+    fn png(img: DynamicImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    // Resizes the 200x200 source to a 100x100 PNG and decodes the output
+    fn round_trip(buf: &[u8], format: ImageFormat, query: &str) -> DynamicImage {
+        let resized = resize_image(
+            buf,
+            params(&format!("w=100&h=100&q=h&{}", query)),
+            format,
+            vec![ImageFormat::Png],
+        )
+        .unwrap();
+
+        image::load_from_memory_with_format(&resized.buffer, image::ImageFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn l8_stays_single_channel() {
+        let src = DynamicImage::ImageLuma8(GrayImage::from_pixel(200, 200, image::Luma([100])));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::L8);
+        assert_eq!(out.as_luma8().unwrap().get_pixel(50, 50).0, [100]);
+    }
+
+    #[test]
+    fn la8_becomes_rgba8() {
+        let src =
+            DynamicImage::ImageLumaA8(GrayAlphaImage::from_pixel(200, 200, LumaA([100, 128])));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::Rgba8);
+        assert_eq!(
+            out.as_rgba8().unwrap().get_pixel(50, 50).0,
+            [100, 100, 100, 128]
+        );
+    }
+
+    #[test]
+    fn l16_keeps_its_depth() {
+        let src =
+            DynamicImage::ImageLuma16(ImageBuffer::from_pixel(200, 200, image::Luma([40000u16])));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::L16);
+        assert_eq!(out.as_luma16().unwrap().get_pixel(50, 50).0, [40000]);
+    }
+
+    #[test]
+    fn rgb16_keeps_its_depth() {
+        let src = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(
+            200,
+            200,
+            Rgb([1000u16, 30000, 65000]),
+        ));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::Rgb16);
+        assert_eq!(
+            out.as_rgb16().unwrap().get_pixel(50, 50).0,
+            [1000, 30000, 65000]
+        );
+    }
+
+    #[test]
+    fn rgba16_keeps_its_depth_and_alpha() {
+        let src = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            200,
+            200,
+            Rgba([1000u16, 30000, 65000, 30000]),
+        ));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::Rgba16);
+        let [r, g, b, a] = out.as_rgba16().unwrap().get_pixel(50, 50).0;
+        assert_eq!(a, 30000);
+        // Premultiplying the alpha for the resize rounds the color by a few units
+        for (value, expected) in [(r, 1000), (g, 30000), (b, 65000)] {
+            assert!(value.abs_diff(expected) <= 4, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn rgb32f_becomes_rgb16() {
+        let src =
+            DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(200, 200, Rgb([0.0, 0.5, 1.0])));
+
+        let resized =
+            resize_single_page(src, &[ImageFormat::Png], 100, 100, &params("q=h")).unwrap();
+        let out = image::load_from_memory(&resized.buffer).unwrap();
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::Rgb16);
+        assert_eq!(
+            out.as_rgb16().unwrap().get_pixel(50, 50).0,
+            [0, 32768, 65535]
+        );
+    }
+
+    #[test]
+    fn cmyk_jpeg_becomes_rgb8() {
+        // Pure red in CMYK, encoded the way Photoshop stores it
+        let cmyk = [0u8, 255, 255, 0].repeat(200 * 200);
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 100)
+            .encode(&cmyk, 200, 200, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+
+        let out = round_trip(&jpeg, ImageFormat::Jpeg, "");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::Rgb8);
+        let [r, g, b] = out.as_rgb8().unwrap().get_pixel(50, 50).0;
+        assert!(r > 240 && g < 15 && b < 15, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn gray_blur_uses_the_single_channel_stride() {
+        // Black left half and white right half, so every row of the blurred output is the same
+        let src = DynamicImage::ImageLuma8(GrayImage::from_fn(200, 200, |x, _| {
+            image::Luma([if x < 100 { 0 } else { 255 }])
+        }));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "b=10");
+
+        assert_eq!(out.dimensions(), (100, 100));
+        assert_eq!(out.color(), ColorType::L8);
+        let out = out.as_luma8().unwrap();
+        let first_row: Vec<u8> = (0..100).map(|x| out.get_pixel(x, 0)[0]).collect();
+        for y in 1..100 {
+            let row: Vec<u8> = (0..100).map(|x| out.get_pixel(x, y)[0]).collect();
+            assert_eq!(row, first_row, "row {}", y);
+        }
+        assert!(first_row[0] < 10 && first_row[99] > 245, "{:?}", first_row);
+        assert!(first_row[45] > 0 && first_row[54] < 255, "{:?}", first_row);
+    }
+
+    #[test]
+    fn rgb8_is_untouched() {
+        let src = DynamicImage::ImageRgb8(RgbImage::from_pixel(200, 200, Rgb([10, 20, 30])));
+
+        let out = round_trip(&png(src), ImageFormat::Png, "");
+
+        assert_eq!(out.color(), ColorType::Rgb8);
+        assert_eq!(out.as_rgb8().unwrap().get_pixel(50, 50).0, [10, 20, 30]);
+    }
+
+    // Transparent red on the left, opaque white on the right
+    fn transparent_edge() -> Vec<u8> {
+        png(DynamicImage::ImageRgba8(image::RgbaImage::from_fn(
+            200,
+            200,
+            |x, _| match x < 100 {
+                true => Rgba([255, 0, 0, 0]),
+                false => Rgba([255, 255, 255, 255]),
+            },
+        )))
+    }
+
+    fn assert_no_dark_fringe(out: &DynamicImage) {
+        let out = out.as_rgba8().unwrap();
+        let edge: Vec<_> = out.pixels().filter(|p| p[3] > 0 && p[3] < 255).collect();
+
+        assert!(!edge.is_empty());
+        for pixel in edge {
+            assert!(pixel.0[..3].iter().all(|c| *c >= 250), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn downscale_keeps_transparent_edges_bright() {
+        let out = round_trip(&transparent_edge(), ImageFormat::Png, "");
+
+        assert_eq!(out.color(), ColorType::Rgba8);
+        assert_no_dark_fringe(&out);
+    }
+
+    #[test]
+    fn blur_keeps_transparent_edges_bright() {
+        let out = round_trip(&transparent_edge(), ImageFormat::Png, "b=10");
+
+        assert_eq!(out.color(), ColorType::Rgba8);
+        assert_no_dark_fringe(&out);
+    }
+
+    #[test]
+    fn redact_blur_keeps_transparent_edges_bright() {
+        let out = round_trip(&transparent_edge(), ImageFormat::Png, "redact=50,0,100,200");
+
+        assert_eq!(out.color(), ColorType::Rgba8);
+        assert_no_dark_fringe(&out);
+    }
+
+    #[test]
+    fn crop_of_a_trimmed_source_smaller_than_the_output() {
+        // White 2000x2000 with a red 200x200 centre, which the trim keeps alone
+        let src = DynamicImage::ImageRgb8(RgbImage::from_fn(2000, 2000, |x, y| {
+            match (900..1100).contains(&x) && (900..1100).contains(&y) {
+                true => Rgb([255, 0, 0]),
+                false => Rgb([255, 255, 255]),
+            }
+        }));
+
+        let resized = resize_image(
+            &png(src),
+            params("trim=true&t=c&w=640&h=400&f=png&q=h"),
+            ImageFormat::Png,
+            vec![ImageFormat::Png],
+        )
+        .unwrap();
+        let out = image::load_from_memory(&resized.buffer).unwrap().to_rgb8();
+
+        assert_eq!(out.dimensions(), (640, 400));
+        for pixel in [
+            out.get_pixel(0, 0),
+            out.get_pixel(320, 200),
+            out.get_pixel(639, 399),
+        ] {
+            assert_eq!(pixel.0, [255, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn malformed_redactions_reject_the_request() {
+        for query in [
+            "redact=100,100,100",
+            "redact=100;100;100;100",
+            "redact=-1,0,10,10",
+        ] {
+            let url = format!("https://example.com/a.png?{}", query);
+            assert!(ResizeParams::from_url(&url).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn redactions_outside_the_image_fail() {
+        let src = DynamicImage::ImageRgb8(RgbImage::new(200, 200));
+
+        let err = resize_image(
+            &png(src),
+            params("w=100&redact=300,0,10,10"),
+            ImageFormat::Png,
+            vec![],
+        )
+        .unwrap_err();
+
+        assert!(err.is::<RegionError>());
+    }
+
+    fn gif(img: DynamicImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Gif)
+            .unwrap();
+        buf
+    }
+
+    fn to_gif(buf: &[u8], format: ImageFormat, query: &str) -> image::RgbaImage {
+        let resized = resize_image(buf, params(query), format, vec![ImageFormat::Gif]).unwrap();
+
+        assert_eq!(resized.format, ImageFormat::Gif);
+        image::load_from_memory_with_format(&resized.buffer, image::ImageFormat::Gif)
+            .unwrap()
+            .to_rgba8()
+    }
+
+    #[test]
+    fn gif_outputs_get_rounded_corners() {
+        let src = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            200,
+            200,
+            Rgba([0, 0, 255, 255]),
+        ));
+
+        let out = to_gif(&gif(src), ImageFormat::Gif, "w=200&h=200&radius=max");
+
+        assert_eq!(out.dimensions(), (200, 200));
+        assert_eq!(out.get_pixel(0, 0)[3], 0);
+        assert_eq!(out.get_pixel(100, 100).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn gif_outputs_get_a_border_and_padding() {
+        let src = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            200,
+            200,
+            Rgba([0, 0, 255, 255]),
+        ));
+
+        let out = to_gif(
+            &gif(src),
+            ImageFormat::Gif,
+            "w=200&h=200&border=10,00ff00&pad=5",
+        );
+
+        assert_eq!(out.dimensions(), (230, 230));
+        // The padding is outside the border
+        assert_eq!(out.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert_eq!(out.get_pixel(10, 10).0, [0, 255, 0, 255]);
+        assert_eq!(out.get_pixel(115, 115).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn mislabelled_avif_is_not_decodable() {
+        let avif = [
+            0x00, 0x00, 0x00, 0x1C, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f', 0x00, 0x00,
+            0x00, 0x00, b'a', b'v', b'i', b'f', b'm', b'i', b'f', b'1',
+        ];
+        let format = detect_format(&avif, Some("image/jpeg"), Some("jpg")).unwrap();
+
+        assert_eq!(format, ImageFormat::Avif);
+        assert!(!format.is_decodable());
+        assert!(ImageFormat::Jpeg.is_decodable());
+    }
+
+    #[test]
+    fn border_and_padding_count_towards_the_output_limit() {
+        let buf = png(DynamicImage::ImageRgb8(RgbImage::new(100, 100)));
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Gif] {
+            let err = resize_image(
+                &buf,
+                params("w=4100&h=4100&pad=1000&border=100"),
+                ImageFormat::Png,
+                vec![format],
+            )
+            .err()
+            .unwrap()
+            .downcast::<LimitError>()
+            .unwrap();
+
+            assert_eq!(err.status_code(), 422);
+        }
+    }
+
+    #[test]
+    fn palette_pngs_round_trip() {
+        let img = image::RgbaImage::from_fn(100, 100, |x, y| match (x < 50, y < 50) {
+            (true, true) => Rgba([255, 0, 0, 255]),
+            (false, true) => Rgba([0, 255, 0, 128]),
+            (true, false) => Rgba([0, 0, 255, 255]),
+            (false, false) => Rgba([0, 0, 0, 0]),
+        });
+        let resized = resize_image(
+            &png(DynamicImage::ImageRgba8(img.clone())),
+            params("w=100&h=100&q=h&palette=16"),
+            ImageFormat::Png,
+            vec![ImageFormat::Png],
+        )
+        .unwrap();
+
+        let reader = png::Decoder::new(resized.buffer.as_slice())
+            .read_info()
+            .unwrap();
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(info.bit_depth, png::BitDepth::Two);
+        // Only the translucent entries are listed, in no particular order
+        let mut trns = info.trns.as_deref().unwrap().to_vec();
+        trns.sort();
+        assert_eq!(trns, [0, 128]);
+
+        let out = image::load_from_memory_with_format(&resized.buffer, image::ImageFormat::Png)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(out, img);
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt::{Display, Formatter};
+
+use image::{DynamicImage, ImageDecoder, ImageError, ImageReader};
+
+use crate::config::CONFIG;
+
+// Bytes per pixel of the largest decoded layout, 16-bit RGBA
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+// Inputs and requests rejected before anything large is allocated, so a small file declaring huge
+// dimensions cannot exhaust the memory of the function
+#[derive(Debug)]
+pub enum LimitError {
+    InputBytes { bytes: usize, max: usize },
+    DecodedPixels { pixels: u64, max: u64 },
+    Frames { frames: u32, max: u32 },
+    OutputPixels { pixels: u64, max: u64 },
+}
+
+impl LimitError {
+    // Oversized sources are rejected as too large, oversized outputs as an invalid request
+    pub fn status_code(&self) -> u16 {
+        match self {
+            LimitError::OutputPixels { .. } => 422,
+            _ => 413,
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            LimitError::InputBytes { .. } => "InputTooLarge",
+            LimitError::DecodedPixels { .. } => "TooManyPixels",
+            LimitError::Frames { .. } => "TooManyFrames",
+            LimitError::OutputPixels { .. } => "OutputTooLarge",
+        }
+    }
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            LimitError::InputBytes { bytes, max } => {
+                write!(f, "Input of {} bytes exceeds the limit of {}", bytes, max)
+            }
+            LimitError::DecodedPixels { pixels, max } => {
+                write!(f, "Image of {} pixels exceeds the limit of {}", pixels, max)
+            }
+            LimitError::Frames { frames, max } => {
+                write!(f, "Image of {} frames exceeds the limit of {}", frames, max)
+            }
+            LimitError::OutputPixels { pixels, max } => {
+                write!(
+                    f,
+                    "Output of {} pixels exceeds the limit of {}",
+                    pixels, max
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+pub fn check_input(bytes: usize) -> Result<(), LimitError> {
+    match bytes > CONFIG.max_input_bytes {
+        true => Err(LimitError::InputBytes {
+            bytes,
+            max: CONFIG.max_input_bytes,
+        }),
+        false => Ok(()),
+    }
+}
+
+pub fn check_pixels(width: u32, height: u32) -> Result<(), LimitError> {
+    let pixels = width as u64 * height as u64;
+
+    match pixels > CONFIG.max_decoded_pixels {
+        true => Err(LimitError::DecodedPixels {
+            pixels,
+            max: CONFIG.max_decoded_pixels,
+        }),
+        false => Ok(()),
+    }
+}
+
+pub fn check_frames(frames: u32) -> Result<(), LimitError> {
+    match frames > CONFIG.max_frames {
+        true => Err(LimitError::Frames {
+            frames,
+            max: CONFIG.max_frames,
+        }),
+        false => Ok(()),
+    }
+}
+
+pub fn check_output(width: u32, height: u32) -> Result<(), LimitError> {
+    let pixels = width as u64 * height as u64;
+
+    match pixels > CONFIG.max_output_pixels {
+        true => Err(LimitError::OutputPixels {
+            pixels,
+            max: CONFIG.max_output_pixels,
+        }),
+        false => Ok(()),
+    }
+}
+
+// Shared with the download, so a source over the limit is not fetched in full
+pub fn max_input_bytes() -> usize {
+    CONFIG.max_input_bytes
+}
+
+// Budget of the decoders' allocations, a full frame at the deepest layout
+pub fn max_alloc() -> u64 {
+    CONFIG.max_decoded_pixels * MAX_BYTES_PER_PIXEL
+}
+
+// Reads the dimensions from the header and only decodes when they are within the limits. The
+// allocation limit also stops decoders whose header understates what they allocate.
+pub fn decode(
+    mut reader: ImageReader<impl std::io::BufRead + std::io::Seek>,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut limits = image::Limits::default();
+    limits.max_alloc = Some(max_alloc());
+    reader.limits(limits);
+
+    let decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    check_pixels(width, height)?;
+
+    DynamicImage::from_decoder(decoder).map_err(|err| match err {
+        ImageError::Limits(_) => LimitError::DecodedPixels {
+            pixels: width as u64 * height as u64,
+            max: CONFIG.max_decoded_pixels,
+        }
+        .into(),
+        err => err.into(),
+    })
+}
@@ -0,0 +1,49 @@
+use fast_image_resize::{
+    create_srgb_mapper, images::Image, PixelComponentMapper, PixelType, ResizeOptions, Resizer,
+};
+use image::DynamicImage;
+use lazy_static::lazy_static;
+
+use crate::config::CONFIG;
+
+lazy_static! {
+    // The mapping tables cover every 8 and 16-bit value, so they are built once per instance
+    static ref SRGB_MAPPER: PixelComponentMapper = create_srgb_mapper();
+}
+
+pub fn enabled(params_linear: Option<bool>) -> bool {
+    params_linear.unwrap_or(CONFIG.linear_resize)
+}
+
+// Linear light needs more than 8 bits not to band in the shadows, so 8-bit images are resampled
+// as 16-bit
+fn linear_type(pixel_type: PixelType) -> PixelType {
+    match pixel_type {
+        PixelType::U8 => PixelType::U16,
+        PixelType::U8x2 => PixelType::U16x2,
+        PixelType::U8x3 => PixelType::U16x3,
+        PixelType::U8x4 => PixelType::U16x4,
+        other => other,
+    }
+}
+
+// Converts the sRGB samples to linear light, resamples them and converts the result back, so fine
+// bright details are not darkened by averaging gamma encoded values. Alpha is left as is.
+pub fn resize(
+    resizer: &mut Resizer,
+    src: &DynamicImage,
+    dst: &mut Image,
+    options: &ResizeOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pixel_type = linear_type(dst.pixel_type());
+
+    let mut linear_src = Image::new(src.width(), src.height(), pixel_type);
+    SRGB_MAPPER.forward_map(src, &mut linear_src)?;
+
+    let mut linear_dst = Image::new(dst.width(), dst.height(), pixel_type);
+    resizer.resize(&linear_src, &mut linear_dst, options)?;
+
+    SRGB_MAPPER.backward_map(&linear_dst, dst)?;
+
+    Ok(())
+}
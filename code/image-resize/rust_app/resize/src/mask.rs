@@ -0,0 +1,67 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Radius {
+    Pixels(u32),
+    Max,
+}
+
+impl Radius {
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value {
+            "max" => Some(Radius::Max),
+            _ => value
+                .parse::<u32>()
+                .ok()
+                .filter(|r| *r > 0)
+                .map(Radius::Pixels),
+        }
+    }
+}
+
+impl Display for Radius {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Radius::Pixels(r) => write!(f, "{}", r),
+            Radius::Max => write!(f, "max"),
+        }
+    }
+}
+
+// Multiplies the alpha channel of an RGBA8 buffer by an anti-aliased rounded rectangle mask
+pub fn apply_radius(rgba: &mut [u8], width: u32, height: u32, radius: Radius) {
+    let max_radius = width.min(height) as f32 / 2.0;
+    let radius = match radius {
+        Radius::Pixels(r) => (r as f32).min(max_radius),
+        Radius::Max => max_radius,
+    };
+
+    if radius <= 0.0 {
+        return;
+    }
+
+    let corner = radius.ceil() as u32;
+
+    for y in 0..height {
+        // Only rows inside the top or bottom corner band can be masked
+        if y >= corner && y < height.saturating_sub(corner) {
+            continue;
+        }
+
+        for x in (0..width).filter(|x| *x < corner || *x >= width.saturating_sub(corner)) {
+            let px = x as f32 + 0.5;
+            let py = y as f32 + 0.5;
+
+            let cx = px.clamp(radius, width as f32 - radius);
+            let cy = py.clamp(radius, height as f32 - radius);
+
+            let distance = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+            let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+
+            if coverage < 1.0 {
+                let alpha = &mut rgba[((y * width + x) * 4 + 3) as usize];
+                *alpha = (*alpha as f32 * coverage).round() as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::io::Cursor;
+
+use image::DynamicImage;
+
+// Reads the EXIF orientation tag (1-8) from a JPEG, PNG, WebP or TIFF container
+pub fn read_orientation(img_buf: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(img_buf))
+        .ok()?;
+
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+// Orientations 5 to 8 turn the image by a quarter, so its displayed width is the stored height
+pub fn swaps_axes(orientation: Option<u32>) -> bool {
+    matches!(orientation, Some(5..=8))
+}
+
+// Rotates and flips the decoded pixels so they match the way the image is meant to be displayed
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt::{Display, Formatter};
+
+use exoquant::{ditherer, optimizer, Color, Histogram, Remapper, SimpleColorSpace};
+use image::ColorType;
+
+use crate::config::CONFIG;
+
+const MAX_COLORS: u16 = 256;
+const MIN_COLORS: u16 = 2;
+
+// Alpha below this is fully transparent in a GIF, which only has one transparent palette entry
+const GIF_ALPHA_THRESHOLD: u8 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Palette {
+    Off,
+    Colors(u16),
+}
+
+impl Palette {
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value {
+            "false" | "off" => Some(Palette::Off),
+            "true" | "on" => Some(Palette::Colors(MAX_COLORS)),
+            _ => value
+                .parse::<u16>()
+                .ok()
+                .map(|colors| Palette::Colors(colors.clamp(MIN_COLORS, MAX_COLORS))),
+        }
+    }
+}
+
+impl Display for Palette {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Palette::Off => write!(f, "off"),
+            Palette::Colors(colors) => write!(f, "{}", colors),
+        }
+    }
+}
+
+struct Indexed {
+    palette: Vec<Color>,
+    indices: Vec<u8>,
+}
+
+fn to_colors(buf: &[u8], color: ColorType) -> Result<Vec<Color>, Box<dyn std::error::Error>> {
+    let colors = match color {
+        ColorType::L8 => buf.iter().map(|l| Color::new(*l, *l, *l, 255)).collect(),
+        ColorType::La8 => buf
+            .chunks_exact(2)
+            .map(|p| Color::new(p[0], p[0], p[0], p[1]))
+            .collect(),
+        ColorType::Rgb8 => buf
+            .chunks_exact(3)
+            .map(|p| Color::new(p[0], p[1], p[2], 255))
+            .collect(),
+        ColorType::Rgba8 => buf
+            .chunks_exact(4)
+            .map(|p| Color::new(p[0], p[1], p[2], p[3]))
+            .collect(),
+        _ => return Err(format!("Unsupported palette color type {:?}", color).into()),
+    };
+
+    Ok(colors)
+}
+
+// Reduces the pixels to at most `max_colors` with a k-means optimized palette of the histogram.
+// Images that already fit are indexed exactly, so flat icons and illustrations stay lossless.
+fn quantize(
+    pixels: &[Color],
+    histogram: Histogram,
+    width: u32,
+    max_colors: usize,
+    dither: bool,
+) -> Indexed {
+    let colorspace = SimpleColorSpace::default();
+
+    let palette: Vec<Color> = if histogram.iter().next().is_none() {
+        vec![Color::new(0, 0, 0, 255)]
+    } else if histogram.iter().count() <= max_colors {
+        histogram.iter().map(|(color, _)| *color).collect()
+    } else {
+        let palette =
+            exoquant::generate_palette(&histogram, &colorspace, &optimizer::KMeans, max_colors);
+        optimizer::Optimizer::optimize_palette(
+            &optimizer::KMeans,
+            &colorspace,
+            &palette,
+            &histogram,
+            8,
+        )
+    };
+
+    // Averaging in float color space can leave opaque entries at 254, which would add a
+    // transparency chunk to images without any
+    let opaque = histogram.iter().all(|(color, _)| color.a == 255);
+    let palette: Vec<Color> = if opaque {
+        palette
+            .into_iter()
+            .map(|c| Color::new(c.r, c.g, c.b, 255))
+            .collect()
+    } else {
+        palette
+    };
+
+    let indices = if dither {
+        Remapper::new(&palette, &colorspace, &ditherer::FloydSteinberg::new())
+            .remap(pixels, width as usize)
+    } else {
+        Remapper::new(&palette, &colorspace, &ditherer::None).remap(pixels, width as usize)
+    };
+
+    let (palette, indices) = exoquant::sort_palette(&palette, &indices);
+
+    Indexed { palette, indices }
+}
+
+// Moves the translucent entries to the front so the PNG transparency chunk stays short
+fn translucent_first(indexed: Indexed) -> Indexed {
+    let mut order: Vec<usize> = (0..indexed.palette.len()).collect();
+    order.sort_by_key(|i| indexed.palette[*i].a == 255);
+
+    let mut mapping = vec![0u8; order.len()];
+    for (new_index, old_index) in order.iter().enumerate() {
+        mapping[*old_index] = new_index as u8;
+    }
+
+    Indexed {
+        palette: order.iter().map(|i| indexed.palette[*i]).collect(),
+        indices: indexed
+            .indices
+            .iter()
+            .map(|i| mapping[*i as usize])
+            .collect(),
+    }
+}
+
+// Packs the indices of each row into the smallest PNG bit depth that fits the palette
+fn pack_rows(indices: &[u8], width: u32, bits: u8) -> Vec<u8> {
+    if bits == 8 {
+        return indices.to_vec();
+    }
+
+    let per_byte = (8 / bits) as usize;
+
+    indices
+        .chunks_exact(width as usize)
+        .flat_map(|row| {
+            row.chunks(per_byte).map(|chunk| {
+                chunk.iter().enumerate().fold(0u8, |byte, (i, index)| {
+                    byte | (index << (8 - bits as usize * (i + 1)))
+                })
+            })
+        })
+        .collect()
+}
+
+// Transparent pixels take the color of the last visible pixel of their row, or of the nearest row
+// with one, so the error diffusion does not spread their hidden color into the visible edges
+fn fill_transparent(pixels: &mut [Color], transparent: &[bool], width: u32) {
+    let width = width as usize;
+
+    let mut empty_rows = Vec::new();
+    for (y, (row, hidden)) in pixels
+        .chunks_exact_mut(width)
+        .zip(transparent.chunks_exact(width))
+        .enumerate()
+    {
+        let Some(first) = hidden.iter().position(|t| !t) else {
+            empty_rows.push(y);
+            continue;
+        };
+
+        let mut last = row[first];
+        for (color, hidden) in row.iter_mut().zip(hidden) {
+            match hidden {
+                true => *color = last,
+                false => last = *color,
+            }
+        }
+    }
+
+    let rows = pixels.len() / width;
+    let Some(first_row) = (0..rows).find(|y| !empty_rows.contains(y)) else {
+        return;
+    };
+
+    for y in empty_rows {
+        let source = if y < first_row { first_row } else { y - 1 };
+        pixels.copy_within(source * width..(source + 1) * width, y * width);
+    }
+}
+
+pub fn dither(params_dither: Option<bool>) -> bool {
+    params_dither.unwrap_or(CONFIG.palette_dither)
+}
+
+pub fn encode_png(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    max_colors: u16,
+    dither: bool,
+    compression: png::Compression,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let pixels = to_colors(buf, color)?;
+    let histogram = pixels.iter().cloned().collect();
+    let indexed = translucent_first(quantize(
+        &pixels,
+        histogram,
+        width,
+        max_colors as usize,
+        dither,
+    ));
+
+    let (bits, depth) = match indexed.palette.len() {
+        0..=2 => (1, png::BitDepth::One),
+        3..=4 => (2, png::BitDepth::Two),
+        5..=16 => (4, png::BitDepth::Four),
+        _ => (8, png::BitDepth::Eight),
+    };
+
+    let rgb: Vec<u8> = indexed
+        .palette
+        .iter()
+        .flat_map(|c| [c.r, c.g, c.b])
+        .collect();
+    let alpha: Vec<u8> = indexed
+        .palette
+        .iter()
+        .take_while(|c| c.a < 255)
+        .map(|c| c.a)
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, width, height);
+
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(rgb);
+    if !alpha.is_empty() {
+        encoder.set_trns(alpha);
+    }
+    encoder.set_compression(compression);
+    // Filters predict neighboring values, which is meaningless for palette indices
+    encoder.set_filter(png::FilterType::NoFilter);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack_rows(&indexed.indices, width, bits))?;
+    writer.finish()?;
+
+    Ok(buffer)
+}
+
+pub fn encode_gif(
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    max_colors: u16,
+    dither: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let pixels = to_colors(buf, color)?;
+
+    let transparent: Vec<bool> = pixels.iter().map(|c| c.a < GIF_ALPHA_THRESHOLD).collect();
+    let has_transparency = transparent.iter().any(|t| *t);
+
+    // Only the visible pixels take part in the palette, the transparent ones share a reserved entry
+    let mut opaque: Vec<Color> = pixels
+        .iter()
+        .map(|c| Color::new(c.r, c.g, c.b, 255))
+        .collect();
+    if has_transparency {
+        fill_transparent(&mut opaque, &transparent, width);
+    }
+    let max_colors = if has_transparency {
+        max_colors - 1
+    } else {
+        max_colors
+    };
+
+    let histogram = opaque
+        .iter()
+        .zip(&transparent)
+        .filter(|(_, transparent)| !**transparent)
+        .map(|(c, _)| *c)
+        .collect();
+    let mut indexed = quantize(&opaque, histogram, width, max_colors as usize, dither);
+
+    let transparent_index = if has_transparency {
+        let index = indexed.palette.len() as u8;
+        indexed.palette.push(Color::new(0, 0, 0, 0));
+        indexed
+            .indices
+            .iter_mut()
+            .zip(&transparent)
+            .filter(|(_, transparent)| **transparent)
+            .for_each(|(i, _)| *i = index);
+
+        Some(index)
+    } else {
+        None
+    };
+
+    let rgb: Vec<u8> = indexed
+        .palette
+        .iter()
+        .flat_map(|c| [c.r, c.g, c.b])
+        .collect();
+
+    let mut buffer = Vec::new();
+    {
+        let mut encoder =
+            gif::Encoder::new(&mut buffer, width.try_into()?, height.try_into()?, &[])?;
+        let frame = gif::Frame::from_palette_pixels(
+            width.try_into()?,
+            height.try_into()?,
+            indexed.indices,
+            rgb,
+            transparent_index,
+        );
+        encoder.write_frame(&frame)?;
+    }
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::gif::GifDecoder, ColorType, DynamicImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn rgba(colors: &[Color]) -> Vec<[u8; 4]> {
+        colors.iter().map(|c| [c.r, c.g, c.b, c.a]).collect()
+    }
+
+    fn indexed(palette: &[Color], indices: &[u8]) -> Indexed {
+        Indexed {
+            palette: palette.to_vec(),
+            indices: indices.to_vec(),
+        }
+    }
+
+    #[test]
+    fn packs_rows_at_one_bit() {
+        let indices = [1, 0, 1, 1, 0, 0, 0, 0, 1, 1, 0, 1, 1, 0, 1, 0, 0, 0, 1, 1];
+
+        assert_eq!(
+            pack_rows(&indices, 10, 1),
+            [0b1011_0000, 0b1100_0000, 0b0110_1000, 0b1100_0000]
+        );
+    }
+
+    #[test]
+    fn packs_rows_at_two_bits() {
+        let indices = [3, 2, 1, 0, 1, 2, 0, 3, 3, 1];
+
+        assert_eq!(
+            pack_rows(&indices, 5, 2),
+            [0b1110_0100, 0b0100_0000, 0b1000_1111, 0b0100_0000]
+        );
+    }
+
+    #[test]
+    fn packs_rows_at_four_bits() {
+        let indices = [15, 1, 7, 8, 0, 12];
+
+        assert_eq!(pack_rows(&indices, 3, 4), [0xF1, 0x70, 0x80, 0xC0]);
+        assert_eq!(pack_rows(&indices, 3, 8), indices);
+    }
+
+    #[test]
+    fn moves_translucent_entries_first() {
+        let palette = [
+            Color::new(255, 0, 0, 255),
+            Color::new(0, 0, 0, 0),
+            Color::new(0, 255, 0, 255),
+            Color::new(0, 0, 255, 128),
+        ];
+        let sorted = translucent_first(indexed(&palette, &[0, 1, 2, 3, 3, 0]));
+
+        assert_eq!(
+            rgba(&sorted.palette),
+            rgba(&[palette[1], palette[3], palette[0], palette[2]])
+        );
+        assert_eq!(sorted.indices, [2, 0, 3, 1, 1, 2]);
+
+        // The transparency chunk covers the translucent prefix only
+        let alpha: Vec<u8> = sorted
+            .palette
+            .iter()
+            .take_while(|c| c.a < 255)
+            .map(|c| c.a)
+            .collect();
+        assert_eq!(alpha, [0, 128]);
+    }
+
+    // A gradient fading out to the right, the invisible half holding `hidden` as its color
+    fn fading_gradient(hidden: [u8; 3]) -> RgbaImage {
+        RgbaImage::from_fn(64, 16, |x, y| match x < 32 {
+            true => Rgba([(x * 8) as u8, (y * 16) as u8, 128, 255]),
+            false => Rgba([hidden[0], hidden[1], hidden[2], 0]),
+        })
+    }
+
+    fn decode_gif(buf: Vec<u8>) -> RgbaImage {
+        let decoder = GifDecoder::new(Cursor::new(buf)).unwrap();
+
+        DynamicImage::from_decoder(decoder).unwrap().to_rgba8()
+    }
+
+    #[test]
+    fn hidden_colors_do_not_bleed_into_dithered_gifs() {
+        let encode = |img: RgbaImage| {
+            let buf = encode_gif(img.as_raw(), 64, 16, ColorType::Rgba8, 8, true).unwrap();
+            decode_gif(buf)
+        };
+
+        let black = encode(fading_gradient([0, 0, 0]));
+        let magenta = encode(fading_gradient([255, 0, 255]));
+
+        assert_eq!(black, magenta);
+        assert!(black.pixels().skip(32).take(32).all(|p| p[3] == 0));
+    }
+
+    #[test]
+    fn fills_transparent_rows_from_their_neighbors() {
+        let red = Color::new(255, 0, 0, 255);
+        let blue = Color::new(0, 0, 255, 255);
+        let hidden = Color::new(0, 255, 0, 255);
+
+        let mut pixels = vec![hidden, hidden, red, hidden, hidden, blue, hidden, hidden];
+        let transparent: Vec<bool> = pixels.iter().map(|c| *c == hidden).collect();
+        fill_transparent(&mut pixels, &transparent, 2);
+
+        assert_eq!(
+            rgba(&pixels),
+            rgba(&[red, red, red, red, blue, blue, blue, blue])
+        );
+    }
+}
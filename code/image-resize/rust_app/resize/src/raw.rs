@@ -0,0 +1,515 @@
+use std::{collections::HashSet, io::Cursor, panic};
+
+use image::{DynamicImage, ImageFormat, ImageReader, RgbImage};
+use imagepipe::{ImageSource, Pipeline};
+use lambda_runtime::tracing;
+
+use crate::{limits, orientation, utils, ResizeParams};
+
+// Bounds the IFD walk of corrupted or looping files
+const MAX_IFDS: usize = 64;
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_PHOTOMETRIC: u16 = 0x0106;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_DNG_VERSION: u16 = 0xC612;
+
+// Photometric interpretations of sensor data, mosaiced or already demosaiced
+const PHOTOMETRIC_CFA: u32 = 32803;
+const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
+
+// Old-style and new-style JPEG compression
+const COMPRESSION_JPEG: [u32; 2] = [6, 7];
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    offset: usize,
+}
+
+// Minimal reader of the TIFF structure most RAW formats are built on. Vendors change the magic
+// number, but keep the byte order mark and the IFD layout.
+struct Tiff<'a> {
+    buf: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(buf: &'a [u8]) -> Option<Self> {
+        match buf {
+            [b'I', b'I', ..] => Some(Tiff {
+                buf,
+                little_endian: true,
+            }),
+            [b'M', b'M', ..] => Some(Tiff {
+                buf,
+                little_endian: false,
+            }),
+            _ => None,
+        }
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.buf.get(offset..offset + 2)?.try_into().ok()?;
+
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.buf.get(offset..offset + 4)?.try_into().ok()?;
+
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    // Values of SHORT, LONG and IFD entries, stored inline when they fit in 4 bytes
+    fn values(&self, entry: &Entry) -> Vec<u32> {
+        let size = match entry.kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return vec![],
+        };
+        let count = (entry.count as usize).min(self.buf.len() / size);
+
+        let start = match count * size {
+            0..=4 => entry.offset,
+            _ => match self.u32(entry.offset) {
+                Some(offset) => offset as usize,
+                None => return vec![],
+            },
+        };
+
+        (0..count)
+            .map_while(|i| match size {
+                2 => self.u16(start + i * 2).map(u32::from),
+                _ => self.u32(start + i * 4),
+            })
+            .collect()
+    }
+
+    fn value(&self, entries: &[Entry], tag: u16) -> Option<u32> {
+        let entry = entries.iter().find(|entry| entry.tag == tag)?;
+        self.values(entry).first().copied()
+    }
+
+    fn entries(&self, offset: usize) -> Option<(Vec<Entry>, u32)> {
+        let count = self.u16(offset)? as usize;
+
+        let entries = (0..count)
+            .map(|i| offset + 2 + i * 12)
+            .map_while(|entry| {
+                Some(Entry {
+                    tag: self.u16(entry)?,
+                    kind: self.u16(entry + 2)?,
+                    count: self.u32(entry + 4)?,
+                    offset: entry + 8,
+                })
+            })
+            .collect();
+        let next = self.u32(offset + 2 + count * 12).unwrap_or(0);
+
+        Some((entries, next))
+    }
+
+    // Visits IFD0, the chained IFDs and their sub IFDs
+    fn ifds(&self) -> Vec<Vec<Entry>> {
+        let mut pending: Vec<u32> = self.u32(4).into_iter().collect();
+        let mut visited = HashSet::new();
+        let mut ifds = vec![];
+
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || !visited.insert(offset) || visited.len() > MAX_IFDS {
+                continue;
+            }
+
+            let Some((entries, next)) = self.entries(offset as usize) else {
+                continue;
+            };
+
+            pending.push(next);
+            if let Some(sub_ifds) = entries.iter().find(|entry| entry.tag == TAG_SUB_IFDS) {
+                pending.extend(self.values(sub_ifds));
+            }
+
+            ifds.push(entries);
+        }
+
+        ifds
+    }
+
+    // Embedded JPEG previews, either referenced by the JPEG interchange tags or stored as the
+    // single strip of a JPEG compressed color IFD
+    fn previews(&self) -> Vec<&'a [u8]> {
+        self.ifds()
+            .iter()
+            .flat_map(|entries| {
+                let interchange = self
+                    .value(entries, TAG_JPEG_OFFSET)
+                    .zip(self.value(entries, TAG_JPEG_LENGTH));
+
+                let photometric = self.value(entries, TAG_PHOTOMETRIC);
+                let sensor = matches!(photometric, Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW));
+                let jpeg = self
+                    .value(entries, TAG_COMPRESSION)
+                    .is_some_and(|compression| COMPRESSION_JPEG.contains(&compression));
+                let strip = match (jpeg && !sensor, self.strip(entries)) {
+                    (true, Some(strip)) => Some(strip),
+                    _ => None,
+                };
+
+                [interchange, strip]
+            })
+            .flatten()
+            .filter_map(|(offset, length)| {
+                self.buf
+                    .get(offset as usize..offset as usize + length as usize)
+            })
+            .filter(|jpeg| jpeg.starts_with(&[0xFF, 0xD8]))
+            .collect()
+    }
+
+    fn strip(&self, entries: &[Entry]) -> Option<(u32, u32)> {
+        let offsets = entries
+            .iter()
+            .find(|entry| entry.tag == TAG_STRIP_OFFSETS)?;
+        let lengths = entries
+            .iter()
+            .find(|entry| entry.tag == TAG_STRIP_BYTE_COUNTS)?;
+
+        match (&self.values(offsets)[..], &self.values(lengths)[..]) {
+            (&[offset], &[length]) => Some((offset, length)),
+            _ => None,
+        }
+    }
+
+    fn has_sensor_data(&self) -> bool {
+        self.ifds().iter().any(|entries| {
+            entries.iter().any(|entry| entry.tag == TAG_DNG_VERSION)
+                || matches!(
+                    self.value(entries, TAG_PHOTOMETRIC),
+                    Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW)
+                )
+        })
+    }
+}
+
+// DNG, NEF, ARW and PEF files are valid TIFFs, so they are told apart by their sensor data
+pub fn is_raw(buf: &[u8]) -> bool {
+    match buf {
+        _ if buf.starts_with(b"FUJIFILMCCD-RAW") => true,
+        [b'I', b'I', b'R', b'O' | b'S', ..] | [b'M', b'M', b'O', b'R', ..] => true,
+        [b'I', b'I', b'U', 0x00, ..] => true,
+        [b'I', b'I', 0x2A, 0x00, _, _, _, _, b'C', b'R', ..] => true,
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => {
+            Tiff::new(buf).is_some_and(|tiff| tiff.has_sensor_data())
+        }
+        _ => false,
+    }
+}
+
+fn demosaic(
+    buf: &[u8],
+    params: &ResizeParams,
+    exif_orientation: Option<u32>,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut raw = rawloader::decode(&mut Cursor::new(buf))?;
+
+    // The sensor data is a single 16-bit plane, the developed image is several times larger
+    limits::check_pixels(raw.width as u32, raw.height as u32)?;
+
+    // The EXIF orientation is applied after decoding like for the other formats, so the pipeline
+    // only rotates the files it can read it from
+    let swap = match exif_orientation {
+        Some(exif) => {
+            raw.orientation = rawloader::Orientation::Normal;
+            orientation::swaps_axes(Some(exif))
+        }
+        None => orientation::swaps_axes(Some(raw.orientation.to_u16() as u32)),
+    };
+
+    let (width, height) = match swap {
+        true => (raw.height, raw.width),
+        false => (raw.width, raw.height),
+    };
+    let scale = utils::cover_scale(width as f32, height as f32, params);
+
+    let mut pipeline = Pipeline::new_from_source(ImageSource::Raw(raw))?;
+
+    // Demosaicing straight at the output size is much faster than developing the full sensor.
+    // Redaction regions are given in sensor pixels, so they keep the full size.
+    if scale < 1.0 && params.redact.is_empty() {
+        let size = (width.max(height) as f32 * scale).ceil() as usize;
+        pipeline.globals.settings.maxwidth = size;
+        pipeline.globals.settings.maxheight = size;
+    }
+
+    let output = pipeline.output_8bit(None)?;
+    let img = RgbImage::from_raw(output.width as u32, output.height as u32, output.data)
+        .ok_or("Invalid RAW output")?;
+
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+fn decode_jpeg(buf: &[u8]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut reader = ImageReader::new(Cursor::new(buf));
+    reader.set_format(ImageFormat::Jpeg);
+
+    limits::decode(reader)
+}
+
+// Uses the largest embedded preview when it covers the requested size, since it is rendered by
+// the camera and cheap to decode. Otherwise the sensor data is developed, falling back to the
+// largest preview for cameras the decoder does not know. Previews are smaller than the sensor, so
+// they are never used for redactions, whose regions would land in the wrong place.
+pub fn decode(
+    buf: &[u8],
+    params: &ResizeParams,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let exif_orientation = orientation::read_orientation(buf);
+
+    let mut previews: Vec<(&[u8], (u32, u32))> = Tiff::new(buf)
+        .filter(|_| params.redact.is_empty())
+        .map(|tiff| tiff.previews())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|jpeg| {
+            let mut reader = ImageReader::new(Cursor::new(jpeg));
+            reader.set_format(ImageFormat::Jpeg);
+
+            reader.into_dimensions().ok().map(|size| (jpeg, size))
+        })
+        .collect();
+    previews.sort_by_key(|(_, (width, height))| std::cmp::Reverse(width * height));
+
+    let covering = previews.first().filter(|(_, (width, height))| {
+        let (width, height) = match orientation::swaps_axes(exif_orientation) {
+            true => (*height, *width),
+            false => (*width, *height),
+        };
+
+        utils::cover_scale(width as f32, height as f32, params) <= 1.0
+    });
+
+    if let Some((jpeg, _)) = covering {
+        if let Ok(img) = decode_jpeg(jpeg) {
+            return Ok(img);
+        }
+    }
+
+    // The RAW decoders trust the offsets in the file, so corrupted uploads can panic
+    let developed = panic::catch_unwind(|| demosaic(buf, params, exif_orientation))
+        .unwrap_or_else(|_| Err("Corrupted camera RAW".into()));
+
+    match (developed, previews.first()) {
+        (Ok(img), _) => Ok(img),
+        (Err(err), Some((jpeg, _))) => {
+            tracing::warn!("RAW decode failed, using the embedded preview: {}", err);
+            decode_jpeg(jpeg)
+        }
+        (Err(err), None) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, GenericImageView, RgbImage};
+
+    use super::*;
+
+    const BYTE: u16 = 1;
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+
+    // Writes a little-endian IFD of single-value entries at `offset`, growing the buffer to fit
+    fn put_ifd(buf: &mut Vec<u8>, offset: usize, entries: &[(u16, u16, u32)], next: u32) {
+        let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+        for (tag, kind, value) in entries {
+            ifd.extend(tag.to_le_bytes());
+            ifd.extend(kind.to_le_bytes());
+            ifd.extend(1u32.to_le_bytes());
+            ifd.extend(value.to_le_bytes());
+        }
+        ifd.extend(next.to_le_bytes());
+
+        put(buf, offset, &ifd);
+    }
+
+    fn put(buf: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if buf.len() < offset + data.len() {
+            buf.resize(offset + data.len(), 0);
+        }
+        buf[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn header() -> Vec<u8> {
+        vec![b'I', b'I', 0x2A, 0x00, 8, 0, 0, 0]
+    }
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        RgbImage::from_pixel(width, height, image::Rgb([200, 100, 50]))
+            .write_with_encoder(JpegEncoder::new(&mut Cursor::new(&mut buf)))
+            .unwrap();
+        buf
+    }
+
+    // A TIFF whose IFD0 references each JPEG through the JPEG interchange tags of its own IFD
+    fn with_previews(jpegs: &[&[u8]]) -> Vec<u8> {
+        let mut buf = header();
+        let mut data_offset = 512;
+
+        for (i, jpeg) in jpegs.iter().enumerate() {
+            let offset = 8 + i * 64;
+            let next = match i + 1 < jpegs.len() {
+                true => offset as u32 + 64,
+                false => 0,
+            };
+            let entries = [
+                (TAG_JPEG_OFFSET, LONG, data_offset as u32),
+                (TAG_JPEG_LENGTH, LONG, jpeg.len() as u32),
+            ];
+
+            put_ifd(&mut buf, offset, &entries, next);
+            put(&mut buf, data_offset, jpeg);
+            data_offset += jpeg.len();
+        }
+
+        buf
+    }
+
+    fn params(query: &str) -> ResizeParams {
+        ResizeParams::from_url(&format!("https://example.com/a.dng?{}", query)).unwrap()
+    }
+
+    #[test]
+    fn walks_looping_ifd_chains() {
+        let mut buf = header();
+        put_ifd(&mut buf, 8, &[(TAG_PHOTOMETRIC, SHORT, 2)], 100);
+        put_ifd(&mut buf, 100, &[(TAG_SUB_IFDS, LONG, 100)], 8);
+
+        assert_eq!(Tiff::new(&buf).unwrap().ifds().len(), 2);
+
+        let mut buf = header();
+        put_ifd(&mut buf, 8, &[(TAG_PHOTOMETRIC, SHORT, 2)], 8);
+
+        assert_eq!(Tiff::new(&buf).unwrap().ifds().len(), 1);
+    }
+
+    #[test]
+    fn stops_at_truncated_entries() {
+        let mut buf = header();
+        put_ifd(
+            &mut buf,
+            8,
+            &[(TAG_COMPRESSION, SHORT, 7), (TAG_PHOTOMETRIC, SHORT, 6)],
+            0,
+        );
+        // The entry count claims a third entry, the buffer ends in the middle of the second
+        buf[8] = 3;
+        buf.truncate(8 + 2 + 12 + 6);
+
+        let tiff = Tiff::new(&buf).unwrap();
+        let ifds = tiff.ifds();
+        assert_eq!(ifds.len(), 1);
+        assert_eq!(ifds[0].len(), 1);
+        assert_eq!(tiff.value(&ifds[0], TAG_COMPRESSION), Some(7));
+        assert!(tiff.previews().is_empty());
+
+        // Values stored out of line past the end of the buffer are dropped, whatever their count
+        let mut buf = header();
+        put(&mut buf, 8, &1000u32.to_le_bytes());
+        let tiff = Tiff::new(&buf).unwrap();
+
+        for count in [4, u32::MAX] {
+            let entry = Entry {
+                tag: TAG_STRIP_OFFSETS,
+                kind: LONG,
+                count,
+                offset: 8,
+            };
+            assert!(tiff.values(&entry).is_empty());
+        }
+    }
+
+    #[test]
+    fn finds_interchange_previews() {
+        let preview = jpeg(64, 48);
+        let buf = with_previews(&[&preview]);
+
+        assert_eq!(Tiff::new(&buf).unwrap().previews(), [preview.as_slice()]);
+
+        // A length running past the end of the file is not a preview
+        let mut buf = buf;
+        buf.truncate(buf.len() - 1);
+        assert!(Tiff::new(&buf).unwrap().previews().is_empty());
+    }
+
+    #[test]
+    fn finds_jpeg_strips_outside_sensor_data() {
+        let preview = jpeg(64, 48);
+
+        let strip = |photometric: u32| {
+            let mut buf = header();
+            let entries = [
+                (TAG_COMPRESSION, SHORT, 7),
+                (TAG_PHOTOMETRIC, SHORT, photometric),
+                (TAG_STRIP_OFFSETS, LONG, 512),
+                (TAG_STRIP_BYTE_COUNTS, LONG, preview.len() as u32),
+            ];
+            put_ifd(&mut buf, 8, &entries, 0);
+            put(&mut buf, 512, &preview);
+            buf
+        };
+
+        let ycbcr = strip(6);
+        assert_eq!(Tiff::new(&ycbcr).unwrap().previews(), [preview.as_slice()]);
+
+        let cfa = strip(PHOTOMETRIC_CFA);
+        assert!(Tiff::new(&cfa).unwrap().previews().is_empty());
+    }
+
+    #[test]
+    fn plain_tiffs_are_not_raw() {
+        let mut tiff = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(16, 16))
+            .write_to(&mut Cursor::new(&mut tiff), ImageFormat::Tiff)
+            .unwrap();
+        assert!(!is_raw(&tiff));
+        assert!(!is_raw(&with_previews(&[&jpeg(16, 16)])));
+
+        let mut cfa = header();
+        put_ifd(&mut cfa, 8, &[(TAG_PHOTOMETRIC, SHORT, PHOTOMETRIC_CFA)], 0);
+        assert!(is_raw(&cfa));
+
+        let mut dng = header();
+        put_ifd(&mut dng, 8, &[(TAG_DNG_VERSION, BYTE, 0x0104)], 0);
+        assert!(is_raw(&dng));
+    }
+
+    #[test]
+    fn uses_the_largest_covering_preview() {
+        let buf = with_previews(&[&jpeg(160, 120), &jpeg(800, 600), &jpeg(320, 240)]);
+
+        let img = decode(&buf, &params("w=400&h=300")).unwrap();
+        assert_eq!(img.dimensions(), (800, 600));
+
+        // No preview covers the size and the sensor data cannot be developed
+        let img = decode(&buf, &params("w=1600&h=1200")).unwrap();
+        assert_eq!(img.dimensions(), (800, 600));
+
+        // Redactions never use a preview
+        assert!(decode(&buf, &params("w=400&h=300&redact=0,0,10,10")).is_err());
+    }
+}
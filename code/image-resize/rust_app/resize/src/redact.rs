@@ -0,0 +1,265 @@
+use std::fmt::{Display, Formatter};
+
+use fast_image_resize::PixelType;
+use image::{imageops, DynamicImage, GenericImageView, RgbaImage};
+use libblur::FastBlurChannels;
+
+use crate::alpha;
+
+const MAX_REGIONS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedactMode {
+    Blur,
+    Pixelate,
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl Display for RedactMode {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RedactMode::Blur => write!(f, "b"),
+            RedactMode::Pixelate => write!(f, "p"),
+        }
+    }
+}
+
+// Regions outside the image would leave what they were meant to hide visible, so they fail the
+// request like malformed ones
+#[derive(Debug)]
+pub struct RegionError {
+    pub region: Region,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Redact region {} is outside the {}x{} image",
+            self.region, self.width, self.height
+        )
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+// Accepts `x,y,width,height` rectangles separated by `;`. Any malformed rectangle rejects the
+// whole value, as dropping it would serve the image unredacted.
+pub fn parse_regions(value: &str) -> Result<Vec<Region>, String> {
+    let regions = value
+        .split(';')
+        .map(|rect| {
+            let values = rect
+                .split(',')
+                .map(|v| v.trim().parse::<u32>().ok())
+                .collect::<Option<Vec<_>>>();
+
+            match values.as_deref() {
+                Some(&[x, y, width, height]) if width > 0 && height > 0 => Ok(Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                }),
+                _ => Err(format!("Invalid redact region '{}'", rect)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match regions.len() {
+        0..=MAX_REGIONS => Ok(regions),
+        count => Err(format!(
+            "{} redact regions exceed the limit of {}",
+            count, MAX_REGIONS
+        )),
+    }
+}
+
+pub fn parse_mode(value: &str) -> Option<RedactMode> {
+    match value {
+        "blur" | "b" => Some(RedactMode::Blur),
+        "pixelate" | "p" => Some(RedactMode::Pixelate),
+        _ => None,
+    }
+}
+
+// Obscures the regions of an image. The regions are in the coordinates of the source image as it
+// is displayed, so this runs after the EXIF orientation is applied and before any trim or crop.
+pub fn redact(
+    img: &mut DynamicImage,
+    regions: &[Region],
+    mode: RedactMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (img_width, img_height) = img.dimensions();
+
+    for region in regions {
+        if region.x >= img_width || region.y >= img_height {
+            return Err(RegionError {
+                region: *region,
+                width: img_width,
+                height: img_height,
+            }
+            .into());
+        }
+
+        let width = region.width.min(img_width - region.x);
+        let height = region.height.min(img_height - region.y);
+
+        let mut pixels = img.view(region.x, region.y, width, height).to_image();
+
+        match mode {
+            RedactMode::Blur => blur(&mut pixels)?,
+            RedactMode::Pixelate => pixelate(&mut pixels),
+        }
+
+        imageops::replace(img, &pixels, region.x.into(), region.y.into());
+    }
+
+    Ok(())
+}
+
+fn blur(pixels: &mut RgbaImage) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = pixels.dimensions();
+    let radius = (width.min(height) / 2).clamp(2, 254);
+
+    alpha::premultiply(pixels, width, height, PixelType::U8x4)?;
+
+    // Several passes so that even large regions leave nothing recognizable
+    for _ in 0..3 {
+        libblur::stack_blur(
+            pixels,
+            width * 4,
+            width,
+            height,
+            radius,
+            FastBlurChannels::Channels4,
+            libblur::ThreadingPolicy::Single,
+        );
+    }
+
+    alpha::unpremultiply(pixels, width, height, PixelType::U8x4)
+}
+
+fn pixelate(pixels: &mut RgbaImage) {
+    let (width, height) = pixels.dimensions();
+    let block = (width.max(height) / 8).max(4);
+
+    for by in (0..height).step_by(block as usize) {
+        for bx in (0..width).step_by(block as usize) {
+            let block_width = block.min(width - bx);
+            let block_height = block.min(height - by);
+
+            let mut sum = [0u64; 4];
+            for y in by..by + block_height {
+                for x in bx..bx + block_width {
+                    let pixel = pixels.get_pixel(x, y);
+                    sum.iter_mut()
+                        .zip(pixel.0)
+                        .for_each(|(s, c)| *s += c as u64);
+                }
+            }
+
+            let count = (block_width * block_height) as u64;
+            let average = sum.map(|s| (s / count) as u8);
+
+            for y in by..by + block_height {
+                for x in bx..bx + block_width {
+                    pixels.put_pixel(x, y, image::Rgba(average));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(
+            parse_regions("10,20,30,40; 0,0,5,5").unwrap(),
+            vec![
+                Region {
+                    x: 10,
+                    y: 20,
+                    width: 30,
+                    height: 40
+                },
+                Region {
+                    x: 0,
+                    y: 0,
+                    width: 5,
+                    height: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_regions_fail() {
+        for value in [
+            "100,100,100",
+            "100;100;100;100",
+            "-10,0,100,100",
+            "0,0,0,100",
+            "0,0,a,100",
+            "0,0,10,10;",
+            "",
+        ] {
+            assert!(parse_regions(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn too_many_regions_fail() {
+        let value = vec!["0,0,1,1"; MAX_REGIONS + 1].join(";");
+
+        assert!(parse_regions(&value).is_err());
+    }
+
+    #[test]
+    fn regions_outside_the_image_fail() {
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::new(100, 100));
+        let region = Region {
+            x: 100,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+
+        let err = redact(&mut img, &[region], RedactMode::Blur).unwrap_err();
+
+        assert!(err.is::<RegionError>());
+    }
+
+    #[test]
+    fn regions_crossing_the_edge_are_clipped() {
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::new(100, 100));
+        let region = Region {
+            x: 90,
+            y: 90,
+            width: 50,
+            height: 50,
+        };
+
+        assert!(redact(&mut img, &[region], RedactMode::Pixelate).is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::ImageFormat;
+
+// ISO-BMFF brands of AVIF stills and sequences
+const AVIF_BRANDS: [&[u8]; 2] = [b"avif", b"avis"];
+
+// Signature box of the JPEG XL container, a bare codestream starts with 0xFF 0x0A
+const JXL_CONTAINER: [u8; 12] = [
+    0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+];
+
+// Detects the format from the magic bytes at the start of the file
+pub fn sniff(buf: &[u8]) -> Option<ImageFormat> {
+    match buf {
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(ImageFormat::Png),
+        [0xFF, 0xD8, 0xFF, ..] => Some(ImageFormat::Jpeg),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageFormat::Gif),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageFormat::Webp),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] if is_avif(buf) => Some(ImageFormat::Avif),
+        #[cfg(feature = "raw")]
+        _ if crate::raw::is_raw(buf) => Some(ImageFormat::Raw),
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(ImageFormat::Tiff),
+        [b'B', b'M', ..] => Some(ImageFormat::Bmp),
+        [0x00, 0x00, 0x01, 0x00, ..] => Some(ImageFormat::Ico),
+        [b'q', b'o', b'i', b'f', ..] => Some(ImageFormat::Qoi),
+        [0xFF, 0x0A, ..] => Some(ImageFormat::Jxl),
+        _ if buf.starts_with(&JXL_CONTAINER) => Some(ImageFormat::Jxl),
+        _ if is_svg(buf) => Some(ImageFormat::Svg),
+        _ => None,
+    }
+}
+
+// SVG is XML, so it may start with a BOM, an XML declaration, comments or a doctype before the
+// root element
+fn is_svg(buf: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&buf[..buf.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with('<') && head.contains("<svg")
+}
+
+// The `ftyp` box lists the major brand followed by the minor version and compatible brands
+fn is_avif(buf: &[u8]) -> bool {
+    let size = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let ftyp = &buf[..size.min(buf.len())];
+
+    ftyp.get(8..12)
+        .into_iter()
+        .chain(ftyp.get(16..).unwrap_or_default().chunks_exact(4))
+        .any(|brand| AVIF_BRANDS.contains(&brand))
+}
+
+// Magic bytes win over the declared Content-Type, which wins over the key extension
+pub fn detect_format(
+    buf: &[u8],
+    content_type: Option<&str>,
+    extension: Option<&str>,
+) -> Option<ImageFormat> {
+    sniff(buf)
+        .or_else(|| content_type.and_then(ImageFormat::from_content_type))
+        .or_else(|| extension.and_then(ImageFormat::from_extension))
+}
@@ -0,0 +1,126 @@
+use image::{DynamicImage, GrayImage};
+
+const WINDOW: u32 = 8;
+const STEP: u32 = 4;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+// Mean structural similarity of the luma channels, computed over 8x8 windows every 4 pixels.
+// Returns 1.0 for identical images.
+pub fn ssim(reference: &DynamicImage, candidate: &DynamicImage) -> f64 {
+    let reference = reference.to_luma8();
+    let candidate = candidate.to_luma8();
+
+    if reference.dimensions() != candidate.dimensions() {
+        return 0.0;
+    }
+
+    let (width, height) = reference.dimensions();
+
+    // Images smaller than a window are compared as a single window
+    let window_width = WINDOW.min(width);
+    let window_height = WINDOW.min(height);
+
+    let mut total = 0.0;
+    let mut count = 0;
+
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            total += window_ssim(&reference, &candidate, x, y, window_width, window_height);
+            count += 1;
+            x += STEP;
+        }
+        y += STEP;
+    }
+
+    if count == 0 {
+        return 1.0;
+    }
+
+    total / count as f64
+}
+
+fn window_ssim(a: &GrayImage, b: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let n = (width * height) as f64;
+
+    let (mut sum_a, mut sum_b) = (0.0, 0.0);
+    let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+
+    for wy in y..y + height {
+        for wx in x..x + width {
+            let pa = a.get_pixel(wx, wy)[0] as f64;
+            let pb = b.get_pixel(wx, wy)[0] as f64;
+
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+
+    let mean_a = sum_a / n;
+    let mean_b = sum_b / n;
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use super::ssim;
+
+    // Diagonal stripes, so every window has some structure
+    fn stripes(width: u32, height: u32, offset: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+            Luma([((x + y + offset) % 8 * 32) as u8])
+        }))
+    }
+
+    #[test]
+    fn identical_images_score_one() {
+        let img = stripes(64, 48, 0);
+
+        assert_eq!(ssim(&img, &img), 1.0);
+    }
+
+    #[test]
+    fn shifted_image_scores_lower() {
+        let score = ssim(&stripes(64, 48, 0), &stripes(64, 48, 3));
+
+        assert!(score < 0.5, "ssim {}", score);
+    }
+
+    #[test]
+    fn noisy_image_scores_lower() {
+        let reference = stripes(64, 48, 0);
+        let mut noisy = reference.to_luma8();
+        for (i, pixel) in noisy.pixels_mut().enumerate() {
+            pixel[0] = pixel[0].saturating_add((i * 7919 % 23) as u8);
+        }
+
+        let score = ssim(&reference, &DynamicImage::ImageLuma8(noisy));
+
+        assert!(score < 1.0 && score > 0.5, "ssim {}", score);
+    }
+
+    #[test]
+    fn images_smaller_than_a_window_are_compared() {
+        let img = stripes(5, 3, 0);
+
+        assert_eq!(ssim(&img, &img), 1.0);
+        assert!(ssim(&img, &stripes(5, 3, 4)) < 1.0);
+    }
+
+    #[test]
+    fn different_sizes_score_zero() {
+        assert_eq!(ssim(&stripes(8, 8, 0), &stripes(16, 8, 0)), 0.0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{borrow::Cow, io::Read, sync::Arc};
+
+use flate2::read::GzDecoder;
+use image::{DynamicImage, RgbaImage};
+use lazy_static::lazy_static;
+use resvg::{tiny_skia, usvg};
+
+use crate::{config::CONFIG, limits, utils, ResizeParams};
+
+// Longest side of a rasterized SVG, extreme aspect ratios would otherwise explode the pixmap
+const MAX_RASTER_SIZE: f32 = 8192.0;
+
+lazy_static! {
+    // Text without a matching font is dropped, so the system fonts are scanned once per instance
+    static ref FONTS: Arc<usvg::fontdb::Database> = {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+
+        // The generic families default to fonts rarely installed on Linux, so they fall back to
+        // any installed family instead
+        let query = usvg::fontdb::Query {
+            families: &[usvg::fontdb::Family::SansSerif],
+            ..Default::default()
+        };
+        let fallback = fonts
+            .faces()
+            .next()
+            .and_then(|face| face.families.first())
+            .map(|(family, _)| family.clone());
+
+        if let (None, Some(family)) = (fonts.query(&query), fallback) {
+            fonts.set_serif_family(family.as_str());
+            fonts.set_sans_serif_family(family.as_str());
+            fonts.set_monospace_family(family.as_str());
+            fonts.set_cursive_family(family.as_str());
+            fonts.set_fantasy_family(family.as_str());
+        }
+
+        Arc::new(fonts)
+    };
+}
+
+// Only embedded data URLs are loaded, references to files or URLs are dropped
+fn options() -> usvg::Options<'static> {
+    let mut options = usvg::Options::default();
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    options.fontdb = FONTS.clone();
+    options.font_family = String::from("sans-serif");
+
+    options
+}
+
+// SVGZ is inflated here instead of by usvg, which reads the whole stream without a limit, so a
+// small gzip bomb cannot expand past the input limit
+fn parse(buf: &[u8]) -> Result<usvg::Tree, Box<dyn std::error::Error>> {
+    let text = match buf.starts_with(&[0x1f, 0x8b]) {
+        true => {
+            let mut text = Vec::new();
+            GzDecoder::new(buf)
+                .take(CONFIG.max_input_bytes as u64 + 1)
+                .read_to_end(&mut text)?;
+            limits::check_input(text.len())?;
+
+            Cow::Owned(text)
+        }
+        false => Cow::Borrowed(buf),
+    };
+
+    let text = std::str::from_utf8(&text)?;
+
+    Ok(usvg::Tree::from_str(text, &options())?)
+}
+
+// Serializes the SVG again from the parsed tree, which only knows about rendering. Scripts, event
+// handlers, foreign objects and external references do not survive the round trip.
+pub fn sanitize(buf: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let tree = parse(buf)?;
+
+    let write_options = usvg::WriteOptions {
+        preserve_text: true,
+        ..Default::default()
+    };
+
+    Ok(tree.to_string(&write_options).into_bytes())
+}
+
+// Renders the SVG at the scale covering the requested size, so the resize afterwards only
+// crops or shrinks it slightly instead of upscaling pixels. Redaction regions are given in the
+// units of the SVG, so redacted requests render it at its own size.
+pub fn rasterize(
+    buf: &[u8],
+    params: &ResizeParams,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let tree = parse(buf)?;
+    let size = tree.size();
+
+    let scale = match params.redact.is_empty() {
+        true => utils::cover_scale(size.width(), size.height(), params)
+            .min(MAX_RASTER_SIZE / size.width().max(size.height())),
+        false => 1.0,
+    };
+
+    let width = (size.width() * scale).ceil().max(1.0) as u32;
+    let height = (size.height() * scale).ceil().max(1.0) as u32;
+
+    limits::check_pixels(width, height)?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("Invalid SVG size")?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // The pixmap is premultiplied
+    let pixels: Vec<u8> = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+
+    let img = RgbaImage::from_raw(width, height, pixels).ok_or("Invalid SVG pixmap")?;
+
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use image::GenericImageView;
+
+    use super::{rasterize, sanitize};
+    use crate::{config::CONFIG, LimitError, ResizeParams};
+
+    const SVG: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" width="400" height="200"><rect width="400" height="200" fill="red"/></svg>"#;
+
+    fn params(query: &str) -> ResizeParams {
+        ResizeParams::from_url(&format!("https://example.com/a.svg?{}", query)).unwrap()
+    }
+
+    #[test]
+    fn renders_at_the_requested_size() {
+        let img = rasterize(SVG, &params("w=100&h=100")).unwrap();
+
+        assert_eq!(img.dimensions(), (200, 100));
+    }
+
+    #[test]
+    fn redactions_render_at_the_svg_size() {
+        let img = rasterize(SVG, &params("w=100&h=100&redact=0,0,50,50")).unwrap();
+
+        assert_eq!(img.dimensions(), (400, 200));
+    }
+
+    fn gzip(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        for chunk in chunks {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn renders_gzipped_svgs() {
+        let img = rasterize(&gzip(&[SVG]), &params("w=100&h=100")).unwrap();
+
+        assert_eq!(img.dimensions(), (200, 100));
+    }
+
+    #[test]
+    fn rejects_gzip_bombs() {
+        let padding = vec![b' '; CONFIG.max_input_bytes];
+        let bomb = gzip(&[&SVG[..SVG.len() - 6], &padding, b"</svg>"]);
+        assert!(bomb.len() < CONFIG.max_input_bytes / 100);
+
+        for err in [
+            sanitize(&bomb).unwrap_err(),
+            rasterize(&bomb, &params("w=100&h=100")).unwrap_err(),
+        ] {
+            let err = err.downcast::<LimitError>().unwrap();
+            assert_eq!(err.status_code(), 413);
+        }
+    }
+}
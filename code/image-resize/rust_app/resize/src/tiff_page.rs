@@ -0,0 +1,137 @@
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageBuffer};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    ColorType,
+};
+
+use crate::limits;
+
+const MAX_PAGE: u32 = 1000;
+
+pub fn parse_page(value: &str) -> Option<u32> {
+    value.parse::<u32>().ok().map(|page| page.min(MAX_PAGE))
+}
+
+// A page past the end of the file, answered with an HTTP error instead of failing the invocation
+#[derive(Debug)]
+pub struct PageError {
+    pub page: u32,
+    pub pages: u32,
+}
+
+impl Display for PageError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "TIFF page {} not found, the file has {} pages",
+            self.page, self.pages
+        )
+    }
+}
+
+impl std::error::Error for PageError {}
+
+// CMYK scans are stored without an ICC profile here, so a naive conversion is used
+fn cmyk_to_rgb(buf: &[u8]) -> Vec<u8> {
+    buf.chunks_exact(4)
+        .flat_map(|p| {
+            let k = 255 - p[3] as u32;
+            [p[0], p[1], p[2]].map(|c| ((255 - c as u32) * k / 255) as u8)
+        })
+        .collect()
+}
+
+// Decodes a single page of a multi-page TIFF, pages are counted from 0. The `image` crate only
+// reads the first page.
+pub fn decode_page(buf: &[u8], page: u32) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    limits::check_frames(page + 1)?;
+
+    let mut decoder = Decoder::new(Cursor::new(buf))?;
+    for pages in 1..=page {
+        if !decoder.more_images() {
+            return Err(PageError { page, pages }.into());
+        }
+        decoder.next_image()?;
+    }
+
+    let (width, height) = decoder.dimensions()?;
+    limits::check_pixels(width, height)?;
+    let color = decoder.colortype()?;
+
+    let img = match (color, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::CMYK(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, cmyk_to_rgb(&buf)).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+        }
+        _ => None,
+    };
+
+    img.ok_or_else(|| format!("Unsupported TIFF color type {:?}", color).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::GenericImageView;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::{decode_page, PageError};
+
+    // Two RGB pages of different sizes
+    fn two_pages() -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut buf)).unwrap();
+        encoder
+            .write_image::<colortype::RGB8>(30, 20, &[255; 30 * 20 * 3])
+            .unwrap();
+        encoder
+            .write_image::<colortype::RGB8>(10, 40, &[0; 10 * 40 * 3])
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn decodes_the_requested_page() {
+        let img = decode_page(&two_pages(), 1).unwrap();
+
+        assert_eq!(img.dimensions(), (10, 40));
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn pages_past_the_end_fail() {
+        let err = decode_page(&two_pages(), 2)
+            .unwrap_err()
+            .downcast::<PageError>()
+            .unwrap();
+
+        assert_eq!((err.page, err.pages), (2, 2));
+    }
+}
@@ -0,0 +1,74 @@
+use image::{DynamicImage, GenericImageView, Rgba};
+use lambda_runtime::tracing;
+
+const DEFAULT_THRESHOLD: u8 = 10;
+
+// Accepts a color-distance threshold, or `true` for the default one
+pub fn parse_threshold(value: &str) -> Option<u8> {
+    match value {
+        "true" => Some(DEFAULT_THRESHOLD),
+        "false" => None,
+        _ => value.parse::<u8>().ok(),
+    }
+}
+
+fn distance(a: Rgba<u8>, b: Rgba<u8>) -> f32 {
+    // Fully transparent pixels are equal whatever their color channels hold
+    if a[3] == 0 && b[3] == 0 {
+        return 0.0;
+    }
+
+    a.0.iter()
+        .zip(b.0.iter())
+        .map(|(x, y)| (*x as f32 - *y as f32).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+// Removes the uniform border around the image. The border color is taken from the top left pixel,
+// anything further than `threshold` from it counts as content.
+pub fn trim(img: DynamicImage, threshold: u8) -> DynamicImage {
+    let (width, height) = img.dimensions();
+
+    if width == 0 || height == 0 {
+        return img;
+    }
+
+    let reference = img.get_pixel(0, 0);
+    let threshold = threshold as f32;
+    let is_content = |x: u32, y: u32| distance(img.get_pixel(x, y), reference) > threshold;
+
+    let Some(top) = (0..height).find(|y| (0..width).any(|x| is_content(x, *y))) else {
+        // The whole image is border, there is nothing sensible to keep
+        return img;
+    };
+    let bottom = (top..height)
+        .rev()
+        .find(|y| (0..width).any(|x| is_content(x, *y)))
+        .unwrap_or(top);
+    let left = (0..width)
+        .find(|x| (top..=bottom).any(|y| is_content(*x, y)))
+        .unwrap_or(0);
+    let right = (left..width)
+        .rev()
+        .find(|x| (top..=bottom).any(|y| is_content(*x, y)))
+        .unwrap_or(left);
+
+    let (trimmed_width, trimmed_height) = (right - left + 1, bottom - top + 1);
+
+    if trimmed_width == width && trimmed_height == height {
+        return img;
+    }
+
+    tracing::info!(
+        "Trimmed {}x{} to {}x{} at {}, {}",
+        width,
+        height,
+        trimmed_width,
+        trimmed_height,
+        left,
+        top
+    );
+
+    img.crop_imm(left, top, trimmed_width, trimmed_height)
+}
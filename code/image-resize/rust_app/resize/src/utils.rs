@@ -1,4 +1,4 @@
-use crate::config::CONFIG;
+use crate::{config::CONFIG, ResizeParams, TransformMode};
 
 pub fn limit(value: u32, low_limit: u32, high_limit: u32, rounding_value: Option<u32>) -> u32 {
     let rounding_value = rounding_value.unwrap_or(CONFIG.rounding_value);
@@ -15,3 +15,15 @@ pub fn limit(value: u32, low_limit: u32, high_limit: u32, rounding_value: Option
 
     result
 }
+
+// Scale at which an image of the given size covers the requested size. Crops without a height
+// use the default one, like the resize itself.
+pub fn cover_scale(width: f32, height: f32, params: &ResizeParams) -> f32 {
+    let width_scale = params.w as f32 / width;
+
+    match (&params.t, params.h) {
+        (_, Some(h)) => width_scale.max(h as f32 / height),
+        (TransformMode::Crop, None) => width_scale.max(CONFIG.default_height as f32 / height),
+        (TransformMode::Fit, None) => width_scale,
+    }
+}
@@ -0,0 +1,187 @@
+use resize::ImageFormat;
+
+use crate::config::CONFIG;
+
+struct MediaRange {
+    media_type: String,
+    subtype: String,
+    q: f32,
+}
+
+impl MediaRange {
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';');
+        let (media_type, subtype) = parts.next()?.trim().to_lowercase().split_once('/').map(
+            |(media_type, subtype)| (media_type.trim().to_string(), subtype.trim().to_string()),
+        )?;
+
+        let q = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .map(|q| q.clamp(0.0, 1.0))
+            .unwrap_or(1.0);
+
+        Some(MediaRange {
+            media_type,
+            subtype,
+            q,
+        })
+    }
+
+    // 2 for an exact match, 1 for `image/*` and 0 for `*/*`
+    fn specificity(&self, format: &ImageFormat) -> Option<u8> {
+        let (media_type, subtype) = format.content_type().split_once('/')?;
+
+        match (self.media_type.as_str(), self.subtype.as_str()) {
+            (t, s) if t == media_type && s == subtype => Some(2),
+            (t, "*") if t == media_type && is_baseline(format) => Some(1),
+            ("*", "*") if is_baseline(format) => Some(0),
+            _ => None,
+        }
+    }
+}
+
+// Browsers send wildcards even when they cannot decode AVIF or WebP, so wildcards only stand for
+// the formats every client decodes
+fn is_baseline(format: &ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif)
+}
+
+// The q-value of the most specific media range matching the format
+fn quality(ranges: &[MediaRange], format: &ImageFormat) -> f32 {
+    ranges
+        .iter()
+        .filter_map(|range| range.specificity(format).map(|s| (s, range.q)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, q)| q)
+        .unwrap_or(0.0)
+}
+
+// Formats acceptable to the client from the configured preference list, ordered by q-value and
+// then by preference. A baseline source format is preferred over the other baseline formats so
+// that e.g. `*/*` does not turn PNGs into JPEGs.
+pub fn accepted_formats(accept: &str, source_format: &ImageFormat) -> Vec<ImageFormat> {
+    rank_formats(accept, source_format, &CONFIG.format_preference)
+}
+
+fn rank_formats(
+    accept: &str,
+    source_format: &ImageFormat,
+    preference: &[ImageFormat],
+) -> Vec<ImageFormat> {
+    let ranges: Vec<MediaRange> = accept.split(',').filter_map(MediaRange::parse).collect();
+
+    let first_baseline = preference.iter().position(is_baseline).unwrap_or(0);
+    let rank = |index: usize, format: &ImageFormat| {
+        if format == source_format && is_baseline(format) {
+            (first_baseline.min(index), 0)
+        } else {
+            (index, 1)
+        }
+    };
+
+    let mut formats: Vec<((usize, u8), f32, ImageFormat)> = preference
+        .iter()
+        .enumerate()
+        .map(|(i, format)| (rank(i, format), quality(&ranges, format), format.clone()))
+        .filter(|(_, q, _)| *q > 0.0)
+        .collect();
+
+    formats.sort_by(|(a_rank, a_q, _), (b_rank, b_q, _)| {
+        b_q.total_cmp(a_q).then_with(|| a_rank.cmp(b_rank))
+    });
+
+    formats.into_iter().map(|(_, _, format)| format).collect()
+}
+
+pub fn negotiate(accept: &str, source_format: &ImageFormat) -> Option<ImageFormat> {
+    accepted_formats(accept, source_format).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use resize::ImageFormat::{self, Avif, Gif, Jpeg, Png, Webp};
+
+    use super::rank_formats;
+
+    const PREFERENCE: [ImageFormat; 5] = [Avif, Webp, Jpeg, Png, Gif];
+
+    const CHROME: &str = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+    const SAFARI: &str = "image/webp,image/avif,image/jxl,image/heic,image/heic-sequence,video/*;q=0.8,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5";
+
+    fn rank(accept: &str, source_format: ImageFormat) -> Vec<ImageFormat> {
+        rank_formats(accept, &source_format, &PREFERENCE)
+    }
+
+    #[test]
+    fn orders_by_q_value() {
+        assert_eq!(
+            rank("image/webp;q=0.5,image/avif;q=0.9", Jpeg),
+            [Avif, Webp]
+        );
+        assert_eq!(rank("image/avif;q=0.5,image/webp", Jpeg), [Webp, Avif]);
+        assert_eq!(rank("image/png;q=0.5,image/*", Png), [Jpeg, Gif, Png]);
+    }
+
+    #[test]
+    fn excludes_q_zero() {
+        assert_eq!(
+            rank("image/avif;q=0,image/webp,*/*", Jpeg),
+            [Webp, Jpeg, Png, Gif]
+        );
+        // The exact media range wins over the wildcard
+        assert_eq!(rank("image/png;q=0,image/*", Png), [Jpeg, Gif]);
+        assert_eq!(rank("*/*;q=0", Jpeg), []);
+    }
+
+    #[test]
+    fn wildcards_only_match_baseline_formats() {
+        assert_eq!(rank("image/*", Jpeg), [Jpeg, Png, Gif]);
+        assert_eq!(rank("*/*", Jpeg), [Jpeg, Png, Gif]);
+        assert_eq!(rank("text/html,application/*", Jpeg), []);
+    }
+
+    #[test]
+    fn wildcards_keep_the_source_format() {
+        assert_eq!(rank("*/*", Png), [Png, Jpeg, Gif]);
+        assert_eq!(rank("*/*", Gif), [Gif, Jpeg, Png]);
+        // A source that is not a baseline format is not preferred
+        assert_eq!(rank("*/*", Webp), [Jpeg, Png, Gif]);
+        assert_eq!(rank("image/webp,*/*", Webp), [Webp, Jpeg, Png, Gif]);
+    }
+
+    #[test]
+    fn ties_follow_the_preference() {
+        let preference = [Webp, Avif, Png, Jpeg];
+
+        assert_eq!(
+            rank_formats("image/avif,image/webp,*/*", &Jpeg, &preference),
+            [Webp, Avif, Jpeg, Png]
+        );
+        // Formats missing from the preference are never picked
+        assert_eq!(
+            rank_formats("image/avif,image/gif", &Gif, &preference[..1]),
+            []
+        );
+    }
+
+    #[test]
+    fn parses_parameters_and_case() {
+        assert_eq!(
+            rank(" Image/WebP ; level=1 ; Q=0.7 , image/avif;q=0.6", Jpeg),
+            [Webp, Avif]
+        );
+        assert_eq!(rank("image/avif;q=2,image/webp;q=oops", Jpeg), [Avif, Webp]);
+        assert_eq!(rank("garbage,,image", Jpeg), []);
+    }
+
+    #[test]
+    fn negotiates_browser_headers() {
+        assert_eq!(rank(CHROME, Jpeg), [Avif, Webp, Jpeg, Png, Gif]);
+        assert_eq!(rank(CHROME, Png), [Avif, Webp, Png, Jpeg, Gif]);
+        // Safari lists PNG explicitly and the other baseline formats at a lower q-value
+        assert_eq!(rank(SAFARI, Jpeg), [Avif, Webp, Png, Jpeg, Gif]);
+        assert_eq!(rank(SAFARI, Gif), [Avif, Webp, Png, Gif, Jpeg]);
+    }
+}
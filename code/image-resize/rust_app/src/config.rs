@@ -1,13 +1,22 @@
 use std::env;
 
 use lazy_static::lazy_static;
+use resize::ImageFormat;
+
+// Camera RAW extensions are only valid when the decoder is compiled in
+#[cfg(feature = "raw")]
+const RAW_EXTENSIONS: &[&str] = &["dng", "cr2", "nef", "nrw", "arw", "orf", "rw2", "raf", "pef", "srw"];
+#[cfg(not(feature = "raw"))]
+const RAW_EXTENSIONS: &[&str] = &[];
 
 pub struct Config {
     #[allow(dead_code)]
     pub region: String,
     pub bucket_access_point: String,
     pub valid_extensions: Vec<String>,
-    pub default_height: u32
+    pub default_height: u32,
+    pub format_preference: Vec<ImageFormat>,
+    pub svg_passthrough: bool,
 }
 
 impl Config {
@@ -17,17 +26,33 @@ impl Config {
         let bucket_access_point = env::var("BUCKET_ACCESS_POINT")
             .expect("BUCKET_ACCESS_POINT environment variable is required");
 
-        let valid_extensions = env::var("VALID_EXTENSIONS").ok().map(|val| val.split(',').map(String::from).collect()).unwrap_or(
-            vec!["jpg", "jpeg", "png", "gif", "webp"].iter().map(|&s| s.to_string()).collect()
+        let valid_extensions = env::var("VALID_EXTENSIONS").ok().map(|val| val.split(',').map(|ext| ext.trim().to_lowercase()).collect()).unwrap_or(
+            ["jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "bmp", "ico", "qoi", "svg", "jxl"].iter().chain(RAW_EXTENSIONS).map(|&s| s.to_string()).collect()
         );
 
         let default_height = env::var("DEFAULT_HEIGHT").ok().and_then(|val: String| val.parse::<u32>().ok()).unwrap_or(400);
 
+        // Order in which output formats are picked when the client accepts them equally
+        let format_preference = env::var("FORMAT_PREFERENCE")
+            .unwrap_or(String::from("avif,webp,jpeg,png,gif"))
+            .split(',')
+            .filter_map(|ext| ImageFormat::from_extension(ext.trim()))
+            .filter(ImageFormat::is_web)
+            .collect();
+
+        // SVGs requested without a size are served as sanitized SVG instead of being rasterized
+        let svg_passthrough = env::var("SVG_PASSTHROUGH")
+            .ok()
+            .and_then(|val| val.parse::<bool>().ok())
+            .unwrap_or(true);
+
         Config {
             region,
             bucket_access_point,
             valid_extensions,
-            default_height
+            default_height,
+            format_preference,
+            svg_passthrough,
         }
     }
 }
@@ -1,21 +1,54 @@
-use std::{error, time::Instant};
+use std::{collections::HashMap, error, time::Instant};
 
 use aws_lambda_events::s3::object_lambda::GetObjectContext;
 use aws_sdk_s3::Client as S3Client;
 use config::CONFIG;
 use lambda_runtime::{run, service_fn, tracing, Error, LambdaEvent};
-use resize::{self, ImageFormat, ResizeParams};
-use s3::{GetFile, GetFileUrl, PutFile, S3ObjectLambdaEvent, SendFile};
+use resize::{
+    self, detect_format, ImageFormat, LimitError, OutputFormat, PageError, RegionError,
+    ResizeParams,
+};
+use s3::{
+    GetFile, GetFileUrl, Metadata, PutFile, S3ObjectLambdaEvent, SendErrorResponse, SendFile,
+};
 use utils::{get_file_extension, get_resized_image_key};
 
+mod accept;
 mod config;
 mod s3;
 mod utils;
 
+// Sources and requests over the configured limits, with redact regions outside the image or with
+// a TIFF page past the end get an HTTP error instead of failing the invocation, other errors are
+// returned as is
+async fn send_client_error<T: SendErrorResponse>(
+    client: &T,
+    route: String,
+    token: String,
+    err: Box<dyn error::Error>,
+) -> Result<String, Box<dyn error::Error>> {
+    let (status_code, error_code) = match err.downcast_ref::<LimitError>() {
+        Some(limit) => (limit.status_code(), limit.error_code()),
+        None if err.is::<RegionError>() => (422, "InvalidRegion"),
+        None if err.is::<PageError>() => (422, "InvalidPage"),
+        None => return Err(err),
+    };
+
+    client
+        .send_error_response(
+            route,
+            token,
+            status_code,
+            error_code.to_string(),
+            err.to_string(),
+        )
+        .await
+}
+
 // ===============================
 //      Main Function Handler
 // ===============================
-async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
+async fn function_handler<T: SendFile + SendErrorResponse + GetFileUrl + GetFile + PutFile>(
     event: LambdaEvent<S3ObjectLambdaEvent>,
     client: &T,
 ) -> Result<String, Box<dyn error::Error>> {
@@ -34,7 +67,15 @@ async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
         .get("Accept")
         .and_then(|v| v.to_str().ok())
         .map(String::from);
-    let params = ResizeParams::from_url(&user_request_url).expect("Invalid resize parameters");
+    // Invalid parameters, e.g. a malformed redact region, must not serve the image as is
+    let params = match ResizeParams::from_url(&user_request_url) {
+        Ok(params) => params,
+        Err(err) => {
+            return client
+                .send_error_response(route, token, 422, "InvalidParameters".to_string(), err)
+                .await
+        }
+    };
 
     tracing::info!(
         "Route: {}, s3_url: {}, user_request_header: {:?}",
@@ -44,11 +85,42 @@ async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
     );
 
     let start = Instant::now();
-    let (image, content_type) = client.get_file_url(&s3_url)?;
+    let (image, content_type) = match client.get_file_url(&s3_url) {
+        Ok(file) => file,
+        Err(err) => return send_client_error(client, route, token, err).await,
+    };
     let duration = start.elapsed();
     tracing::info!("Image loaded. Length: {}", image.len());
     tracing::info!("Get file time: {:?}", duration);
 
+    // Keys without an extension are processed whenever their content is a supported image
+    let image_extension = get_file_extension(user_request_url.as_str());
+    let valid_extension = image_extension
+        .as_ref()
+        .is_none_or(|ext| CONFIG.valid_extensions.contains(&ext.to_lowercase()));
+
+    let image_format = detect_format(&image, content_type.as_deref(), image_extension.as_deref());
+
+    // SVGs can carry scripts, so they are never served as uploaded. Sanitizing is cheaper than a
+    // cache lookup, so the vector output is not stored.
+    let vector_svg = params.o || !valid_extension || (CONFIG.svg_passthrough && !params.sized);
+    if image_format == Some(ImageFormat::Svg) && vector_svg {
+        let svg = match resize::sanitize_svg(&image) {
+            Ok(svg) => svg,
+            Err(err) => return send_client_error(client, route, token, err).await,
+        };
+
+        return client
+            .send_file(
+                route,
+                token,
+                svg,
+                ImageFormat::Svg.content_type(),
+                None,
+            )
+            .await;
+    }
+
     if params.o {
         return client
             .send_file(
@@ -58,14 +130,16 @@ async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
                 content_type
                     .unwrap_or(String::from("application/octet-stream"))
                     .as_str(),
+                None,
             )
             .await;
     }
 
-    let image_extension =
-        get_file_extension(user_request_url.as_str()).expect("No image extension found");
-
-    if !CONFIG.valid_extensions.contains(&image_extension) {
+    // Sources without a decoder, e.g. AVIF uploaded without an extension, are returned unchanged
+    let Some(image_format) = image_format
+        .filter(|_| valid_extension)
+        .filter(ImageFormat::is_decodable)
+    else {
         return client
             .send_file(
                 route,
@@ -74,32 +148,48 @@ async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
                 content_type
                     .unwrap_or(String::from("application/octet-stream"))
                     .as_str(),
+                None,
             )
             .await;
-    }
+    };
 
     let image_slice = image.as_slice();
-    let image_format =
-        ImageFormat::from_extension(image_extension.as_str()).expect("Unknown image format");
-    let resized_image_key = get_resized_image_key(&s3_url, &params, accepted_content_type.clone());
-    let resized_image: Vec<u8>;
+
+    // The cache key holds the negotiated formats instead of the raw header, so that every Accept
+    // variant producing the same output shares one cached image. With `f=auto` every accepted
+    // format is a candidate, an explicit format ignores the header altogether.
+    let target_formats: Vec<ImageFormat> = match (&params.f, accepted_content_type.as_deref()) {
+        (Some(OutputFormat::Format(format)), _) => vec![format.clone()],
+        (Some(OutputFormat::Auto), Some(accept)) => accept::accepted_formats(accept, &image_format),
+        (None, Some(accept)) => accept::negotiate(accept, &image_format)
+            .into_iter()
+            .collect(),
+        (_, None) => vec![],
+    };
+
+    let negotiated_accept = match params.f {
+        Some(OutputFormat::Format(_)) => None,
+        _ if target_formats.is_empty() => None,
+        _ => Some(
+            target_formats
+                .iter()
+                .map(|f| f.extension())
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+    };
+
+    let resized_image_key = get_resized_image_key(&s3_url, &params, negotiated_accept);
 
     tracing::info!("Trying to retrive resized image with key {resized_image_key:?}");
 
-    let default_content_type = format!(
-        "image/{}",
-        if image_extension.is_empty() {
-            "*"
-        } else {
-            image_extension.as_str()
-        }
-    );
+    let default_content_type = image_format.content_type().to_string();
 
     match client
         .get_file(resized_image_key.as_str(), &CONFIG.bucket_access_point)
         .await
     {
-        Ok((image, ct)) => {
+        Ok((image, ct, metadata)) => {
             tracing::info!("Retrieved resized image");
 
             client
@@ -108,38 +198,41 @@ async fn function_handler<T: SendFile + GetFileUrl + GetFile + PutFile>(
                     token,
                     image,
                     ct.unwrap_or(default_content_type).as_str(),
+                    metadata,
                 )
                 .await
         }
         Err(_) => {
             let start = Instant::now();
 
-            resized_image = resize::resize_image(
-                image_slice,
-                params,
-                image_format,
-                accepted_content_type.clone().map(ImageFormat::from),
-            )
-            .expect("Image resize failed");
+            let resized_image =
+                match resize::resize_image(image_slice, params, image_format, target_formats) {
+                    Ok(resized_image) => resized_image,
+                    Err(err) => return send_client_error(client, route, token, err).await,
+                };
 
-            let resized_image_content_type = accepted_content_type
-                .unwrap_or_else(|| content_type.clone().unwrap_or(default_content_type));
+            let resized_image_content_type = resized_image.format.content_type();
+            let metadata: Metadata = resized_image
+                .quality
+                .map(|quality| HashMap::from([("quality".to_string(), quality.to_string())]));
 
             let duration = start.elapsed();
             tracing::info!("Process time: {:?}", duration);
 
             let put_file_future = client.put_file(
                 resized_image_key.as_str(),
-                resized_image.clone(),
-                resized_image_content_type.as_str(),
+                resized_image.buffer.clone(),
+                resized_image_content_type,
                 &CONFIG.bucket_access_point,
+                metadata.clone(),
             );
 
             let send_file_future = client.send_file(
                 route,
                 token,
-                resized_image.clone(),
-                resized_image_content_type.as_str(),
+                resized_image.buffer,
+                resized_image_content_type,
+                metadata,
             );
 
             let (_, send_file_result) =
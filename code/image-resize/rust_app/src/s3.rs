@@ -6,7 +6,10 @@ use aws_sdk_s3::{
 };
 use lambda_runtime::tracing;
 use serde::{Deserialize, Serialize};
-use std::{error, io::Read};
+use std::{collections::HashMap, error, io::Read};
+
+// User metadata sent back to the client as `x-amz-meta-*` headers
+pub type Metadata = Option<HashMap<String, String>>;
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -17,7 +20,11 @@ pub struct S3ObjectLambdaEvent {
 
 #[async_trait]
 pub trait GetFile {
-    async fn get_file(&self, key: &str, bucket: &str) -> Result<(Vec<u8>, Option<String>), String>;
+    async fn get_file(
+        &self,
+        key: &str,
+        bucket: &str,
+    ) -> Result<(Vec<u8>, Option<String>, Metadata), String>;
 }
 
 pub trait GetFileUrl {
@@ -32,6 +39,7 @@ pub trait PutFile {
         img_buf: Vec<u8>,
         content_type: &str,
         bucket: &str,
+        metadata: Metadata,
     ) -> Result<String, Box<dyn std::error::Error>>;
 }
 
@@ -43,13 +51,13 @@ pub trait SendFile {
         token: String,
         vec: Vec<u8>,
         content_type: &str,
+        metadata: Metadata,
     ) -> Result<String, Box<dyn error::Error>>;
 }
 
-// TODO: Implement this trait
 #[async_trait]
 pub trait SendErrorResponse {
-  async fn send_error_response(
+    async fn send_error_response(
         &self,
         route: String,
         token: String,
@@ -63,15 +71,17 @@ impl GetFileUrl for S3Client {
     fn get_file_url(&self, url: &str) -> Result<(Vec<u8>, Option<String>), Box<dyn error::Error>> {
         tracing::info!("[S3] Get file url {}", url);
 
-        let resp = match ureq::get(url).call() {
-            Ok(resp) => resp,
-            Err(e) => return Err(e.into()),
-        };
+        let resp = ureq::get(url).call()?;
 
         let len: usize = resp.header("Content-Length").unwrap().parse()?;
+        resize::check_input(len)?;
+
         let content_type = resp.header("Content-Type").map(|ct| ct.to_owned());
         let mut bytes: Vec<u8> = Vec::with_capacity(len);
-        std::io::Read::take(resp.into_reader(), 50_000_000).read_to_end(&mut bytes)?;
+        // One byte past the limit tells a truncated read from a body of exactly the limit
+        std::io::Read::take(resp.into_reader(), resize::max_input_bytes() as u64 + 1)
+            .read_to_end(&mut bytes)?;
+        resize::check_input(bytes.len())?;
 
         tracing::info!("[S3] Got {} bytes", bytes.len());
 
@@ -81,7 +91,11 @@ impl GetFileUrl for S3Client {
 
 #[async_trait]
 impl GetFile for S3Client {
-    async fn get_file(&self, key: &str, bucket: &str) -> Result<(Vec<u8>, Option<String>), String> {
+    async fn get_file(
+        &self,
+        key: &str,
+        bucket: &str,
+    ) -> Result<(Vec<u8>, Option<String>, Metadata), String> {
         tracing::info!("[S3] Get file bucket {}, key {}", bucket, key);
 
         let output = self.get_object().bucket(bucket).key(key).send().await;
@@ -90,9 +104,10 @@ impl GetFile for S3Client {
             Ok(response) => {
                 let bytes = response.body.collect().await.unwrap().to_vec();
                 let content_type = response.content_type;
+                let metadata = response.metadata;
                 tracing::info!("[S3] Object is downloaded, size is {}", bytes.len());
 
-                Ok((bytes, content_type))
+                Ok((bytes, content_type, metadata))
             }
             Err(sdk_error) => {
                 let service_err = sdk_error.into_service_error();
@@ -113,6 +128,7 @@ impl SendFile for S3Client {
         token: String,
         vec: Vec<u8>,
         content_type: &str,
+        metadata: Metadata,
     ) -> Result<String, Box<dyn error::Error>> {
         tracing::info!(
             "[S3] Send file route {}, token {}, length {}",
@@ -130,6 +146,7 @@ impl SendFile for S3Client {
             .status_code(200)
             .body(bytes)
             .content_type(content_type)
+            .set_metadata(metadata)
             .send()
             .await;
 
@@ -144,6 +161,45 @@ impl SendFile for S3Client {
     }
 }
 
+#[async_trait]
+impl SendErrorResponse for S3Client {
+    async fn send_error_response(
+        &self,
+        route: String,
+        token: String,
+        status_code: u16,
+        error_code: String,
+        error_message: String,
+    ) -> Result<String, Box<dyn error::Error>> {
+        tracing::info!(
+            "[S3] Send error response route {}, status {}, code {}: {}",
+            route,
+            status_code,
+            error_code,
+            error_message
+        );
+
+        let write = self
+            .write_get_object_response()
+            .request_route(route)
+            .request_token(token)
+            .status_code(status_code.into())
+            .error_code(error_code)
+            .error_message(error_message)
+            .send()
+            .await;
+
+        match write {
+            Ok(_) => Ok("Error response sent.".to_string()),
+            Err(sdk_error) => {
+                check_write_object_response_error(sdk_error);
+
+                Err("WriteGetObjectResponse creation error".into())
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl PutFile for S3Client {
     async fn put_file(
@@ -152,6 +208,7 @@ impl PutFile for S3Client {
         img_buf: Vec<u8>,
         content_type: &str,
         bucket: &str,
+        metadata: Metadata,
     ) -> Result<String, Box<dyn std::error::Error>> {
         tracing::info!("put file bucket {}, key {}", bucket, key);
         let bytes = ByteStream::from(img_buf);
@@ -161,6 +218,7 @@ impl PutFile for S3Client {
             .key(key)
             .body(bytes)
             .content_type(content_type)
+            .set_metadata(metadata)
             .send()
             .await;
 
@@ -9,14 +9,47 @@ use url::Url;
 
 use crate::config::CONFIG;
 
+// Bumped whenever the same parameters render a different image, so that variants cached by an
+// earlier version are never served. 1 applies the EXIF orientation to every output.
+const RENDER_VERSION: u32 = 1;
+
 #[derive(Deserialize, Serialize)]
 pub struct ImageProcessParams {
+    v: u32,
     w: u32,
     h: u32,
     m: String,
     q: String,
     b: u32,
     a: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    border: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pad: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trim: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redact: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progressive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subsampling: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maxbytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    f: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    palette: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dither: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linear: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<u32>,
 }
 
 pub fn get_file_extension(url_str: &str) -> Option<String> {
@@ -42,45 +75,46 @@ pub fn get_url_path(url_str: &str) -> Option<String> {
 pub fn get_resized_image_key(url: &str, params: &ResizeParams, accept: Option<String>) -> String {
     let path = get_url_path(url).unwrap();
 
-    let url_re = Regex::new(r"(.*)\.(.*)").unwrap();
-    let url_prefix_re = Regex::new(r"(.*)\/(.*)\.(.*)").unwrap();
-
-    let has_prefix = url_re.is_match(url);
-
-    let mut prefix = "";
-    let file_name;
-    let extension;
+    // Legacy objects may have no extension, in which case the file name is kept as is
+    let path_re = Regex::new(r"^(.*)/([^/]+?)(?:\.([^./]+))?$").unwrap();
+    let groups = path_re.captures(path.as_str()).expect("Invalid object path");
 
-    if has_prefix {
-        let groups = url_prefix_re.captures(path.as_str()).unwrap();
-
-        prefix = groups.get(1).unwrap().as_str();
-        file_name = groups.get(2).unwrap().as_str();
-        extension = groups.get(3).unwrap().as_str();
-    } else {
-        let group = url_re.captures(path.as_str()).unwrap();
-
-        file_name = group.get(1).unwrap().as_str();
-        extension = group.get(2).unwrap().as_str();
-    }
+    let prefix = groups.get(1).unwrap().as_str();
+    let file_name = groups.get(2).unwrap().as_str();
+    let extension = groups.get(3).map(|ext| format!(".{}", ext.as_str()));
+    let extension = extension.unwrap_or_default();
 
     let params = ImageProcessParams {
+        v: RENDER_VERSION,
         w: params.w,
         h: params.h.unwrap_or(CONFIG.default_height),
         m: params.t.to_string(),
         q: params.q.to_string(),
         b: params.b,
-        a: accept
+        a: accept,
+        r: params.radius.map(|r| r.to_string()),
+        bg: params.bg.map(|bg| bg.to_string()),
+        border: params.border.map(|border| border.to_string()),
+        pad: Some(params.pad).filter(|pad| *pad > 0),
+        trim: params.trim,
+        redact: (!params.redact.is_empty()).then(|| {
+            let regions: Vec<String> = params.redact.iter().map(|r| r.to_string()).collect();
+            format!("{}:{}", params.redact_mode, regions.join(";"))
+        }),
+        progressive: params.progressive,
+        subsampling: params.subsampling.map(|s| s.to_string()),
+        maxbytes: params.maxbytes,
+        f: params.f.as_ref().map(|f| f.to_string()),
+        palette: params.palette.map(|palette| palette.to_string()),
+        dither: params.dither,
+        linear: params.linear,
+        page: Some(params.page).filter(|page| *page > 0),
     };
 
     let params_json = serde_json::to_string(&params).expect("Unable to serialize params");
     let encoded_params = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(params_json);
 
-    let resize_object_key = if has_prefix {
-        format!("{prefix}/{encoded_params}/{file_name}.{extension}")
-    } else {
-        format!("{encoded_params}/{file_name}.{extension}")
-    };
+    let resize_object_key = format!("{prefix}/{encoded_params}/{file_name}{extension}");
 
     tracing::info!("Resize key: {}", resize_object_key);
 
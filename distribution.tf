@@ -30,7 +30,22 @@ resource "aws_cloudfront_cache_policy" "image_distribution" {
           "t",
           "q",
           "o",
-          "b"
+          "b",
+          "radius",
+          "bg",
+          "border",
+          "pad",
+          "trim",
+          "redact",
+          "redact_mode",
+          "progressive",
+          "subsampling",
+          "maxbytes",
+          "f",
+          "palette",
+          "dither",
+          "page",
+          "linear"
         ]
       }
     }
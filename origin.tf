@@ -68,6 +68,22 @@ resource "aws_lambda_function" "image_resizing_lambda" {
       DEFAULT_HEIGHT       = var.default_height
       DEFAULT_QUALITY      = var.default_quality
       DEFAULT_TRANSFORM    = var.default_transform
+      DEFAULT_BACKGROUND   = var.default_background
+      JPEG_QUALITY         = var.jpeg_quality
+      JPEG_PROGRESSIVE     = var.jpeg_progressive
+      PNG_QUALITY          = var.png_quality
+      AVIF_QUALITY         = var.avif_quality
+      WEBP_QUALITY         = var.webp_quality
+      AUTO_QUALITY_SSIM    = var.auto_quality_ssim
+      PNG_OPTIMIZE_TIMEOUT = var.png_optimize_timeout
+      PALETTE_DITHER       = var.palette_dither
+      LINEAR_RESIZE        = var.linear_resize
+      MAX_INPUT_BYTES      = var.max_input_bytes
+      MAX_DECODED_PIXELS   = var.max_decoded_pixels
+      MAX_FRAMES           = var.max_frames
+      MAX_OUTPUT_PIXELS    = coalesce(var.max_output_pixels, var.max_width * var.max_height)
+      FORMAT_PREFERENCE    = var.format_preference
+      SVG_PASSTHROUGH      = var.svg_passthrough
       AWS_LAMBDA_LOG_LEVEL = var.log_level
     }
   }
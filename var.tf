@@ -78,6 +78,102 @@ variable "default_transform" {
   default     = "f"
 }
 
+variable "default_background" {
+  type        = string
+  description = "The background color in hex used when flattening transparent images to opaque formats"
+  default     = "ffffff"
+}
+
+variable "jpeg_quality" {
+  type        = string
+  description = "The JPEG quality used for the low, medium, high and best quality levels, comma separated"
+  default     = "25,50,75,100"
+}
+
+variable "jpeg_progressive" {
+  type        = bool
+  description = "Encode progressive JPEGs unless the progressive query parameter says otherwise"
+  default     = true
+}
+
+variable "png_quality" {
+  type        = string
+  description = "The PNG compression effort (1-100) used for the low, medium, high and best quality levels, comma separated"
+  default     = "25,50,75,100"
+}
+
+variable "avif_quality" {
+  type        = string
+  description = "The AVIF quality used for the low, medium, high and best quality levels, comma separated"
+  default     = "30,45,60,80"
+}
+
+variable "webp_quality" {
+  type        = string
+  description = "The lossy WebP quality used for the low, medium, high and best quality levels, comma separated. Empty keeps WebP lossless for them"
+  default     = ""
+}
+
+variable "auto_quality_ssim" {
+  type        = number
+  description = "The minimum SSIM between the resized image and its encoding when the auto quality is used"
+  default     = 0.97
+}
+
+variable "png_optimize_timeout" {
+  type        = number
+  description = "The time budget in milliseconds of the lossless optimization of best quality PNGs, 0 disables it"
+  default     = 2000
+}
+
+variable "palette_dither" {
+  type        = bool
+  description = "Whether palette PNG and GIF outputs are dithered by default"
+  default     = true
+}
+
+variable "linear_resize" {
+  type        = bool
+  description = "Whether images are resampled in linear light by default"
+  default     = false
+}
+
+variable "max_input_bytes" {
+  type        = number
+  description = "The size in bytes above which source images are rejected with 413"
+  default     = 50000000
+}
+
+variable "max_decoded_pixels" {
+  type        = number
+  description = "The width times height above which source images are rejected with 413 before decoding"
+  default     = 25000000
+}
+
+variable "max_frames" {
+  type        = number
+  description = "The number of frames or pages above which source images are rejected with 413"
+  default     = 100
+}
+
+variable "max_output_pixels" {
+  type        = number
+  description = "The width times height above which requested outputs are rejected with 422, defaults to max_width times max_height"
+  default     = null
+}
+
+variable "svg_passthrough" {
+  type        = bool
+  description = "Whether SVGs requested without a size are served as sanitized SVG instead of being rasterized"
+  default     = true
+}
+
+variable "format_preference" {
+  type        = string
+  description = "The output formats in order of preference when the Accept header allows several equally, comma separated"
+  default     = "avif,webp,jpeg,png,gif"
+}
+
 variable "log_level" {
   type        = string
   description = "The log level to be used in lambda function. Valid values [ERROR,INFO,DEBUG,TRACE]"